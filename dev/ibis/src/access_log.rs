@@ -0,0 +1,252 @@
+use crate::config::IbisAccessLogFormat;
+use crate::middleware::{ self, Middleware, MiddlewareLayer, Next };
+use crate::request_id::RequestId;
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use std::time::{ Duration, Instant };
+
+use axum::async_trait;
+use axum::body::{ self, Body, BoxBody, Bytes, HttpBody };
+use axum::extract::ConnectInfo;
+use axum::http::{ header, HeaderMap, Request };
+use axum::response::Response;
+
+use hyper::body::SizeHint;
+
+use tracing::info;
+
+
+// アクセスログのtarget（別ファイルへの振り分けに使う）
+pub(crate) const TARGET: &str = "ibis::access";
+
+
+//=============================================================================
+// アクセスログを出力するLayer
+//
+// レスポンスのボディを送り終えた（またはクライアントが切断した）時点で
+// 1リクエストにつき1行を出力する。
+//=============================================================================
+pub(crate) fn layer(format: IbisAccessLogFormat) -> MiddlewareLayer
+{
+    middleware::layer(AccessLog { format })
+}
+
+
+//=============================================================================
+// AccessLog
+//=============================================================================
+struct AccessLog
+{
+    format: IbisAccessLogFormat,
+}
+
+#[async_trait]
+impl Middleware for AccessLog
+{
+    async fn handle(&self, request: Request<Body>, next: Next) -> Response
+    {
+        let start = Instant::now();
+        let entry = Entry::new(self.format, &request);
+
+        let response = next.run(request).await;
+        let entry = entry.status(&response);
+
+        response.map(|inner| body::boxed(AccessLogBody
+        {
+            inner,
+            bytes: 0,
+            start,
+            entry: Some(entry),
+        }))
+    }
+}
+
+
+//=============================================================================
+// Entry
+//
+// アクセスログの1行分の情報
+//=============================================================================
+struct Entry
+{
+    format: IbisAccessLogFormat,
+    method: String,
+    path: String,
+    version: String,
+    peer: Option<SocketAddr>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+    status: u16,
+}
+
+impl Entry
+{
+    //=========================================================================
+    // リクエストから作成
+    //=========================================================================
+    fn new(format: IbisAccessLogFormat, request: &Request<Body>) -> Self
+    {
+        let headers = request.headers();
+
+        Self
+        {
+            format,
+            method: request.method().to_string(),
+            path: request.uri()
+                .path_and_query()
+                .map(|path| path.to_string())
+                .unwrap_or_else(|| "/".to_string()),
+            version: format!("{:?}", request.version()),
+            peer: request.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| *peer),
+            referer: header_value(headers, header::REFERER.as_str()),
+            user_agent: header_value(headers, header::USER_AGENT.as_str()),
+            request_id: request.extensions()
+                .get::<RequestId>()
+                .map(|request_id| request_id.to_string()),
+            status: 0,
+        }
+    }
+
+    //=========================================================================
+    // レスポンスのステータスを設定
+    //=========================================================================
+    fn status(mut self, response: &Response) -> Self
+    {
+        self.status = response.status().as_u16();
+        self
+    }
+
+    //=========================================================================
+    // 出力
+    //=========================================================================
+    fn log(self, bytes: u64, latency: Duration)
+    {
+        let peer = self.peer
+            .map(|peer| peer.ip().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+
+        match self.format
+        {
+            // host ident user [time] "request" status bytes "referer" "user-agent"
+            // の後ろにレイテンシ（ミリ秒）とリクエストIDを付ける
+            IbisAccessLogFormat::Combined =>
+            {
+                info!(
+                    target: TARGET,
+                    "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {:.3} {}",
+                    peer,
+                    chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                    self.method,
+                    escape(&self.path),
+                    self.version,
+                    self.status,
+                    if bytes == 0 { "-".to_string() } else { bytes.to_string() },
+                    escape(self.referer.as_deref().unwrap_or("-")),
+                    escape(self.user_agent.as_deref().unwrap_or("-")),
+                    latency_ms,
+                    self.request_id.as_deref().unwrap_or("-"),
+                );
+            },
+            IbisAccessLogFormat::Kv =>
+            {
+                info!(
+                    target: TARGET,
+                    method = %self.method,
+                    path = ?self.path,
+                    version = %self.version,
+                    status = self.status,
+                    bytes,
+                    latency_ms = %format_args!("{:.3}", latency_ms),
+                    peer = %peer,
+                    referer = ?self.referer.as_deref().unwrap_or("-"),
+                    user_agent = ?self.user_agent.as_deref().unwrap_or("-"),
+                    request_id = %self.request_id.as_deref().unwrap_or("-"),
+                );
+            },
+        }
+    }
+}
+
+
+//=============================================================================
+// ヘッダの値を文字列で取得
+//=============================================================================
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String>
+{
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+
+//=============================================================================
+// 引用符の中に出力する値をエスケープ
+//=============================================================================
+fn escape(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+//=============================================================================
+// AccessLogBody
+//
+// 送ったバイト数を数え、dropされたときにアクセスログを出力する
+//=============================================================================
+struct AccessLogBody
+{
+    inner: BoxBody,
+    bytes: u64,
+    start: Instant,
+    entry: Option<Entry>,
+}
+
+impl HttpBody for AccessLogBody
+{
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Self::Data, Self::Error>>>
+    {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &poll
+        {
+            self.bytes += data.len() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Result<Option<HeaderMap>, Self::Error>>
+    {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool
+    {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint
+    {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for AccessLogBody
+{
+    fn drop(&mut self)
+    {
+        if let Some(entry) = self.entry.take()
+        {
+            entry.log(self.bytes, self.start.elapsed());
+        }
+    }
+}
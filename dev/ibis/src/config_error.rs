@@ -0,0 +1,179 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+
+//=============================================================================
+// ConfigError
+//=============================================================================
+#[derive(Debug)]
+pub enum ConfigError
+{
+    // 設定ファイルが存在しない
+    NotFound
+    {
+        path: String,
+    },
+
+    // 設定ファイルが読み込めない
+    Io
+    {
+        path: String,
+        source: io::Error,
+    },
+
+    // TOMLとして解釈できない（行・列は1始まり）
+    Parse
+    {
+        path: String,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+
+    // 指定したセクションが存在しない
+    MissingSection
+    {
+        section: String,
+    },
+
+    // [server]や[logger]のkindが未知の値
+    UnknownKind
+    {
+        section: String,
+        kind: String,
+    },
+
+    // セクション内の項目の型や名前が不正
+    Field
+    {
+        section: String,
+        message: String,
+    },
+}
+
+impl ConfigError
+{
+    //=========================================================================
+    // TOMLのパースエラーから作成
+    //=========================================================================
+    pub(crate) fn parse(path: &str, e: toml::de::Error) -> Self
+    {
+        let (line, column) = match e.line_col()
+        {
+            Some((line, column)) => (Some(line + 1), Some(column + 1)),
+            None => (None, None),
+        };
+
+        Self::Parse
+        {
+            path: path.to_string(),
+            line,
+            column,
+            message: e.to_string(),
+        }
+    }
+
+    //=========================================================================
+    // セクションのデシリアライズエラーから作成
+    //=========================================================================
+    pub(crate) fn field(section: &str, e: impl fmt::Display) -> Self
+    {
+        Self::Field
+        {
+            section: section.to_string(),
+            message: e.to_string(),
+        }
+    }
+}
+
+//=============================================================================
+// Display実装
+//=============================================================================
+impl fmt::Display for ConfigError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Self::NotFound { path } =>
+            {
+                write!(f, "config file not found: {}", path)
+            },
+            Self::Io { path, source } =>
+            {
+                write!(f, "can't read config file {}: {}", path, source)
+            },
+            Self::Parse { path, line: Some(line), column: Some(column), message } =>
+            {
+                write!(f, "can't parse {} (line {}, column {}): {}", path, line, column, message)
+            },
+            Self::Parse { path, message, .. } =>
+            {
+                write!(f, "can't parse {}: {}", path, message)
+            },
+            Self::MissingSection { section } =>
+            {
+                write!(f, "not found [{}] section", section)
+            },
+            Self::UnknownKind { section, kind } =>
+            {
+                write!(f, "invalid {} kind ({})", section, kind)
+            },
+            Self::Field { section, message } =>
+            {
+                write!(f, "invalid [{}] section: {}", section, message)
+            },
+        }
+    }
+}
+
+//=============================================================================
+// Error実装
+//=============================================================================
+impl error::Error for ConfigError
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+        match self
+        {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+
+    //=========================================================================
+    // パースエラーの行・列は1始まりで報告する
+    //=========================================================================
+    #[test]
+    fn parse_error_reports_line_and_column()
+    {
+        let source = "[tokio]\nport = \"8000\"\nworker_threads = = 5\n";
+        let e = toml::from_str::<toml::Value>(source).unwrap_err();
+
+        let error = ConfigError::parse("config/config.toml", e);
+        match &error
+        {
+            ConfigError::Parse { path, line, column, .. } =>
+            {
+                assert_eq!(path, "config/config.toml");
+                assert_eq!(*line, Some(3));
+                assert_eq!(*column, Some(18));
+            },
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(
+            error.to_string().starts_with("can't parse config/config.toml (line 3, column 18): "),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,16 +1,42 @@
-use crate::config;
+use crate::access_log;
+use crate::config::{ self, IbisAccessLogFormat, IbisConfig, IbisServerTokioConfig };
+use crate::database;
+use crate::error::{ self, ErrorPages };
+use crate::extract;
+use crate::request_id;
+use crate::server::{ BoxIo, IbisServer, TokioServer };
+use crate::shutdown::{ self, Shutdown, ShutdownHook, ShutdownSignal };
+use crate::sse;
+use crate::tls;
 
+use std::sync::Arc;
 use std::time::Duration;
-use std::file;
 use std::str::FromStr;
 
 use tokio::runtime::Builder;
-use tokio::net::TcpListener;
-use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::watch;
 
-use tracing::{ Level, info, warn, error };
-use tracing_subscriber::FmtSubscriber;
+use axum::body::Body;
+use axum::http::header::HeaderName;
+use axum::{ Extension, Router };
+
+use tokio_rustls::TlsAcceptor;
+
+use tracing::{ Level, debug, info, warn, error };
+use tracing_subscriber::Registry;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::{ Layer, SubscriberExt };
+
+
+// 終了コード
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+
+// TLSハンドシェイクのタイムアウト
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 
 //=============================================================================
@@ -29,10 +55,70 @@ impl IbisCore
     }
 
 
+    //=========================================================================
+    // アクセスログだけを書き込むLayer
+    //
+    // combined形式は行の中に時刻を含むので、そのまま書き込む。
+    //=========================================================================
+    fn access_log_layer<W>(config: &IbisConfig, writer: W)
+        -> Box<dyn Layer<Registry> + Send + Sync>
+        where
+            W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let filter = filter_fn(|metadata| metadata.target() == access_log::TARGET);
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(false)
+            .with_level(false)
+            .with_target(false);
+
+        match config.get_logger_access_log_format()
+        {
+            Some(IbisAccessLogFormat::Kv) => layer
+                .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
+                .with_filter(filter)
+                .boxed(),
+            _ => layer
+                .without_time()
+                .with_filter(filter)
+                .boxed(),
+        }
+    }
+
+
     //=========================================================================
     // アプリケーションの起動
     //=========================================================================
-    pub fn run()
+    pub fn run(
+        config: IbisConfig,
+        server: Arc<dyn IbisServer>,
+        router: Router<Body>,
+        error_pages: ErrorPages,
+        shutdown_hooks: Vec<ShutdownHook>,
+    )
+    {
+        // --print-configが指定されていれば有効な設定を出力して終了
+        if config::cli_flag("--print-config")
+        {
+            print!("{}", config.dump());
+            return;
+        }
+
+        let exit_code = Self::start(config, server, router, error_pages, shutdown_hooks);
+        std::process::exit(exit_code);
+    }
+
+
+    //=========================================================================
+    // サーバの起動から終了まで（終了コードを返す）
+    //=========================================================================
+    fn start(
+        config: IbisConfig,
+        server: Arc<dyn IbisServer>,
+        router: Router<Body>,
+        error_pages: ErrorPages,
+        shutdown_hooks: Vec<ShutdownHook>,
+    ) -> i32
     {
         println!(r"
 >>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
@@ -52,9 +138,6 @@ author: Ichigo
 >>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
         ", Self::get_version());
 
-        // 設定値の初期化
-        let config = config::IbisConfig::init();
-
         let log_level = match tracing::Level::from_str(config.get_logger_log_level())
         {
             Ok(level) => level,
@@ -68,33 +151,79 @@ author: Ichigo
 
         //=====================================================================
         // Tracingの設定
-        let logfile = tracing_appender::rolling::daily(
-            config.get_logger_logfile_path(),
-            config.get_logger_logfile_name()
+        // （ファイルへの書き込みは別スレッドで行い、_log_guardのdrop時にflushされる）
+        let (logfile, _log_guard) = tracing_appender::non_blocking(
+            tracing_appender::rolling::daily(
+                config.get_logger_logfile_path(),
+                config.get_logger_logfile_name()
+            )
         );
         let stdout = std::io::stdout.with_max_level(log_level);
 
-        let subscriber = FmtSubscriber::builder()
+        // access_logfile_nameが指定されていればアクセスログはそのファイルにだけ書き込む
+        let access_logfile_name = config.get_logger_access_logfile_name();
+        let separate_access_log = !access_logfile_name.is_empty();
+        let (access_log, _access_log_guard) = match separate_access_log
+        {
+            true =>
+            {
+                let (access_logfile, guard) = tracing_appender::non_blocking(
+                    tracing_appender::rolling::daily(
+                        config.get_logger_logfile_path(),
+                        access_logfile_name
+                    )
+                );
+                (Some(Self::access_log_layer(&config, access_logfile)), Some(guard))
+            },
+            false => (None, None),
+        };
+
+        // アクセスログはlog_levelに関わらず出力する
+        let app_log = tracing_subscriber::fmt::layer()
             .with_writer(stdout.and(logfile))
-            .with_max_level(log_level)
             .with_timer(tracing_subscriber::fmt::time::UtcTime::rfc_3339())
             .with_thread_ids(true)
             .with_thread_names(true)
             .with_file(true)
             .with_line_number(true)
-            .finish();
+            .with_filter(filter_fn(move |metadata|
+            {
+                match metadata.target() == access_log::TARGET
+                {
+                    true => !separate_access_log,
+                    false => *metadata.level() <= log_level,
+                }
+            }));
+
+        let subscriber = Registry::default()
+            .with(access_log)
+            .with(app_log);
         tracing::subscriber::set_global_default(subscriber)
             .expect("setting default subscriber failed");
 
+        // Lenientモードで無視した設定の誤り
+        for warning in &config.warnings
+        {
+            warn!("{}", warning);
+            info!("use default config");
+        }
+
         info!("Start {} (version: {})",
             config.get_app_name(),
             config.get_app_version()
         );
 
+        // 開発環境ではエラーの詳細をページに表示する
+        error::init(config.is_development());
+        if config.is_development()
+        {
+            warn!("running in development mode: error details are shown to clients");
+        }
+
         //=====================================================================
         // Tokioのランタイム
         let runtime = match Builder::new_multi_thread()
-            .enable_io()
+            .enable_all()
             .worker_threads(config.get_server_worker_threads())
             .max_blocking_threads(config.get_server_blocking_threads())
             .thread_name(format!("{}-thread", config.get_app_name()))
@@ -108,12 +237,93 @@ author: Ichigo
                 Err(e) =>
                 {
                     error!("runtime error: {}", e);
-                    return;
+                    return EXIT_FAILURE;
                 },
             };
 
-        runtime.block_on(async
+        let exit_code = runtime.block_on(async
         {
+            // コネクションプールを作成してハンドラから使えるようにする
+            let pool = match config.get_database_config()
+            {
+                Some(database_config) =>
+                {
+                    match database::connect(database_config).await
+                    {
+                        Ok(pool) =>
+                        {
+                            info!("connected to database");
+                            Some(pool)
+                        },
+                        Err(e) =>
+                        {
+                            error!("database connection error: {}", e);
+                            return EXIT_FAILURE;
+                        },
+                    }
+                },
+                None => None,
+            };
+            let router = match &pool
+            {
+                Some(pool) => router.layer(Extension(pool.clone())),
+                None => router,
+            };
+
+            // リクエストボディのサイズを制限する
+            let router = match config.get_server_max_body_size()
+            {
+                0 => router,
+                max_body_size => router.layer(extract::body_limit_layer(max_body_size)),
+            };
+
+            // Sseのストリームはシャットダウンが通知されたら終了する
+            let router = router.layer(sse::layer());
+
+            // エラーのレスポンスはクライアントに応じた形式にする
+            let router = router.layer(error::layer(error_pages));
+
+            // アクセスログはすべてのミドルウェアの外側で記録する
+            let router = match config.get_logger_access_log_format()
+            {
+                Some(format) => router.layer(access_log::layer(format)),
+                None => router,
+            };
+
+            // リクエストIDはアクセスログからも参照するため、さらに外側で付与する
+            let request_id_header = match HeaderName::from_str(config.get_logger_request_id_header())
+            {
+                Ok(header) => header,
+                Err(e) =>
+                {
+                    error!("invalid request_id_header: {}", e);
+                    return EXIT_FAILURE;
+                },
+            };
+            let router = router.layer(request_id::layer(request_id_header));
+
+            // TLSの設定（[tls]セクションがあればHTTPSで待ち受ける）
+            let tls = match config.get_tls_config()
+            {
+                Some(tls_config) =>
+                {
+                    match tls::acceptor(tls_config, &server.alpn_protocols())
+                    {
+                        Ok(acceptor) =>
+                        {
+                            tls::log_expiry(&tls_config.cert_path);
+                            Some(acceptor)
+                        },
+                        Err(e) =>
+                        {
+                            error!("tls error: {:#}", e);
+                            return EXIT_FAILURE;
+                        },
+                    }
+                },
+                None => None,
+            };
+
             // アドレスとポートをTcpListenerにバインディング
             let listener = match TcpListener::bind(format!(
                 "{}:{}",
@@ -125,61 +335,183 @@ author: Ichigo
                 Err(e) =>
                 {
                     error!("tcp listener error: {}", e);
-                    return;
+                    return EXIT_FAILURE;
                 },
             };
-            info!("listening on: {}:{}",
+            info!("listening on: {}:{} ({}{})",
                 config.get_server_address(),
-                config.get_server_port()
+                config.get_server_port(),
+                config.get_server_kind(),
+                if tls.is_some() { ", tls" } else { "" }
             );
 
+            let shutdown = Shutdown::new();
+
+            // 証明書を差し替えられるようにwatchチャネルで共有する
+            let tls = tls.map(|acceptor|
+            {
+                let (sender, receiver) = watch::channel(acceptor);
+                if let Some(tls_config) = config.get_tls_config()
+                {
+                    tokio::spawn(tls::watch(
+                        tls_config.clone(),
+                        server.alpn_protocols(),
+                        sender,
+                        shutdown.signal(),
+                    ));
+                }
+                receiver
+            });
+
+            // HTTPSへリダイレクトするHTTPのリスナー
+            if let Some(redirect_port) = config.get_tls_config()
+                .map(|tls_config| tls_config.redirect_port.as_str())
+                .filter(|port| !port.is_empty())
+            {
+                let address = format!("{}:{}", config.get_server_address(), redirect_port);
+                let listener = match TcpListener::bind(&address).await
+                {
+                    Ok(listener) => listener,
+                    Err(e) =>
+                    {
+                        error!("tcp listener error: {}", e);
+                        return EXIT_FAILURE;
+                    },
+                };
+                info!("redirecting to https on: {}", address);
+
+                let router = tls::redirect_router(config.get_server_port().to_string());
+                tokio::spawn(Self::redirect(listener, router, shutdown.signal()));
+            }
+            let signal = shutdown::wait_for_signal();
+            tokio::pin!(signal);
+
+            let mut exit_code = EXIT_SUCCESS;
             loop
             {
-                // ソケットと接続先情報の取得
-                let (mut socket, data) = match listener.accept().await
+                // ソケットと接続先情報の取得（シグナルを受信したら受付を停止）
+                let accepted = tokio::select!
+                {
+                    accepted = listener.accept() => accepted,
+                    _ = &mut signal => break,
+                };
+                let (socket, data) = match accepted
                 {
                     Ok((socket, data)) => (socket, data),
                     Err(e) =>
                     {
                         error!("application error: {}", e);
-                        return;
+                        exit_code = EXIT_FAILURE;
+                        break;
                     },
                 };
 
                 info!("accept: {}", data);
 
+                let server = server.clone();
+                let router = router.clone();
+                let tls = tls.as_ref().map(|tls| tls.borrow().clone());
+                let signal = shutdown.signal();
                 tokio::spawn(async move
                 {
-                    let mut buf = [0; 1024];
-
-                    loop
+                    let io: BoxIo = match tls
                     {
-                        // クライアントのリクエスト（ソケット）を書き出し
-                        let _n = match socket.read(&mut buf).await
-                        {
-                            Ok(n) if n == 0 => return,  // ソケットがclose()
-                            Ok(n) => n,
-                            Err(e) =>
-                            {
-                                error!("failed to read from socket: {}", e);
-                                return;
-                            },
-                        };
-
-                        let response = "test";
-
-                        // クライアントへのレスポンス
-                        if let Err(e) = socket.write_all(
-                            response.as_bytes()
-                        ).await
+                        Some(acceptor) => match Self::handshake(acceptor, socket).await
                         {
-                            error!("failed to write to socket: {}", e);
-                            return;
-                        }
-                    }
+                            Some(io) => io,
+                            None => return,
+                        },
+                        None => Box::new(socket),
+                    };
+                    server.serve_connection(io, data, router, signal).await;
                 });
             }
-        })
+
+            //=================================================================
+            // シャットダウン
+            drop(listener);
+            info!("shutting down (waiting for active connections)");
+
+            let timeout = Duration::from_secs(config.get_server_shutdown_timeout());
+            if !shutdown.drain(timeout).await
+            {
+                warn!("shutdown timed out after {} seconds", timeout.as_secs());
+                exit_code = EXIT_FAILURE;
+            }
+
+            // 登録されたシャットダウン処理
+            for hook in shutdown_hooks
+            {
+                hook().await;
+            }
+
+            // コネクションプールを閉じる
+            if let Some(pool) = pool
+            {
+                pool.close().await;
+                info!("database pool closed");
+            }
+
+            exit_code
+        });
+
+        // 残りのタスクを破棄してランタイムを停止
+        runtime.shutdown_timeout(Duration::from_secs(1));
+
+        info!("Stop {} (exit code: {})", config.get_app_name(), exit_code);
+        exit_code
     }
-}
 
+
+    //=========================================================================
+    // TLSハンドシェイク
+    //=========================================================================
+    async fn handshake(acceptor: TlsAcceptor, socket: TcpStream) -> Option<BoxIo>
+    {
+        let peer = socket.peer_addr().ok();
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await
+        {
+            Ok(Ok(stream)) => Some(Box::new(stream)),
+            Ok(Err(e)) =>
+            {
+                debug!("tls handshake error from {:?}: {}", peer, e);
+                None
+            },
+            Err(_) =>
+            {
+                debug!("tls handshake timed out from {:?}", peer);
+                None
+            },
+        }
+    }
+
+
+    //=========================================================================
+    // HTTPのリクエストをHTTPSへリダイレクトする
+    //=========================================================================
+    async fn redirect(listener: TcpListener, router: Router<Body>, mut shutdown: ShutdownSignal)
+    {
+        let server = TokioServer::new(&IbisServerTokioConfig::default());
+        loop
+        {
+            let accepted = tokio::select!
+            {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.recv() => break,
+            };
+            match accepted
+            {
+                Ok((socket, data)) =>
+                {
+                    tokio::spawn(server.serve_connection(
+                        Box::new(socket),
+                        data,
+                        router.clone(),
+                        shutdown.clone(),
+                    ));
+                },
+                Err(e) => error!("redirect listener error: {}", e),
+            }
+        }
+    }
+}
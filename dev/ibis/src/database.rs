@@ -0,0 +1,79 @@
+use crate::config::IbisDatabaseConfig;
+use crate::extract::Rejection;
+
+use std::ops::Deref;
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::extract::{ FromRequest, RequestParts };
+use axum::http::StatusCode;
+
+use sqlx::mysql::{ MySqlPool, MySqlPoolOptions };
+
+
+//=============================================================================
+// コネクションプールの作成
+//
+// 接続を1本確立できるまで待つので、DBに到達できなければエラーを返す。
+//=============================================================================
+pub(crate) async fn connect(config: &IbisDatabaseConfig)
+    -> Result<MySqlPool, sqlx::Error>
+{
+    MySqlPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .idle_timeout(Duration::from_secs(config.idle_timeout))
+        .connect(&config.url)
+        .await
+}
+
+
+//=============================================================================
+// Db
+//
+// ハンドラの引数に指定するとコネクションプールを受け取れる。
+// ```
+// async fn count_users(db: ibis::Db) -> String
+// {
+//      let (n,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users")
+//          .fetch_one(&*db)
+//          .await
+//          .unwrap();
+//      n.to_string()
+// }
+// ```
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Db(pub MySqlPool);
+
+impl Deref for Db
+{
+    type Target = MySqlPool;
+
+    fn deref(&self) -> &Self::Target
+    {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Db
+    where
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        // [database]セクションがなければプールは登録されていない
+        match req.extensions().get::<MySqlPool>()
+        {
+            Some(pool) => Ok(Self(pool.clone())),
+            None => Err(Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database is not configured",
+            )),
+        }
+    }
+}
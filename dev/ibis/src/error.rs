@@ -0,0 +1,1495 @@
+use crate::extract::Rejection;
+use crate::middleware::{ self, Middleware, MiddlewareLayer, Next };
+use crate::request_id::RequestId;
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::{ Arc, RwLock };
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+use askama::Template;
+
+use axum::async_trait;
+use axum::body::{ boxed, Body, BoxBody, Full, HttpBody };
+use axum::extract::ConnectInfo;
+use axum::http::response::Parts;
+use axum::http::{ header, HeaderMap, HeaderValue, Request, StatusCode, Uri };
+use axum::response::{ IntoResponse, Response };
+
+use futures_util::FutureExt;
+
+use serde::Serialize;
+use serde_json::{ Map, Value };
+
+use tracing::{ debug, error };
+
+
+// 本文をエラーのメッセージとして扱うレスポンスの最大長
+const MAX_PLAIN_ERROR_SIZE: u64 = 4096;
+
+// ステータスとエラーコードの対応
+const CODES: &[(StatusCode, &str)] = &[
+    (StatusCode::BAD_REQUEST, "bad_request"),
+    (StatusCode::UNAUTHORIZED, "unauthorized"),
+    (StatusCode::FORBIDDEN, "forbidden"),
+    (StatusCode::NOT_FOUND, "not_found"),
+    (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"),
+    (StatusCode::NOT_ACCEPTABLE, "not_acceptable"),
+    (StatusCode::REQUEST_TIMEOUT, "request_timeout"),
+    (StatusCode::CONFLICT, "conflict"),
+    (StatusCode::GONE, "gone"),
+    (StatusCode::LENGTH_REQUIRED, "length_required"),
+    (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+    (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type"),
+    (StatusCode::UNPROCESSABLE_ENTITY, "unprocessable_entity"),
+    (StatusCode::TOO_MANY_REQUESTS, "too_many_requests"),
+    (StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+    (StatusCode::NOT_IMPLEMENTED, "not_implemented"),
+    (StatusCode::SERVICE_UNAVAILABLE, "service_unavailable"),
+    (StatusCode::GATEWAY_TIMEOUT, "gateway_timeout"),
+];
+
+// デバッグページで値を表示しないリクエストヘッダ（名前で判定できないもの）
+const REDACTED_HEADERS: &[header::HeaderName] = &[
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+// 名前にこれらを含むヘッダやクエリパラメータは値を表示しない
+// （Authorization、X-Api-Key、X-Auth-Token、?password=など）
+const SENSITIVE_NAMES: &[&str] = &["auth", "key", "token", "secret", "pass", "session"];
+
+// 伏せた値の代わりに表示する文字列
+const REDACTED: &str = "[redacted]";
+
+// 開発環境で実行しているか（エラーの詳細を記録して表示する）
+static DEVELOPMENT: AtomicBool = AtomicBool::new(false);
+
+// problem+jsonを返すグループのパスのプレフィックス（ErrorTargetで使う）
+static PROBLEM_PREFIXES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+thread_local!
+{
+    // パニックフックで取得したバックトレース（開発環境のみ）
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+
+//=============================================================================
+// 実行環境の設定
+//
+// 開発環境では5xxのエラーのバックトレースを記録し、パニックしたハンドラの
+// バックトレースもパニックフックで取得する。
+//=============================================================================
+pub(crate) fn init(development: bool)
+{
+    DEVELOPMENT.store(development, Ordering::Relaxed);
+    if !development
+    {
+        return;
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info|
+    {
+        PANIC_BACKTRACE.with(|backtrace|
+        {
+            *backtrace.borrow_mut() = Some(Backtrace::force_capture());
+        });
+        default_hook(info);
+    }));
+}
+
+
+//=============================================================================
+// problem+jsonを返すグループのパスのプレフィックスを設定
+//=============================================================================
+pub(crate) fn set_problem_prefixes(prefixes: Vec<String>)
+{
+    if let Ok(mut current) = PROBLEM_PREFIXES.write()
+    {
+        *current = prefixes;
+    }
+}
+
+
+//=============================================================================
+// パスがプレフィックスの下にあるか
+//=============================================================================
+pub(crate) fn is_under(path: &str, prefix: &str) -> bool
+{
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+
+//=============================================================================
+// 開発環境で実行しているか
+//=============================================================================
+fn is_development() -> bool
+{
+    DEVELOPMENT.load(Ordering::Relaxed)
+}
+
+
+//=============================================================================
+// ステータスに対応するエラーコード
+//=============================================================================
+fn code_for(status: StatusCode) -> &'static str
+{
+    CODES.iter()
+        .find(|(s, _)| *s == status)
+        .map(|(_, code)| *code)
+        .unwrap_or(if status.is_client_error() { "bad_request" } else { "internal_error" })
+}
+
+
+//=============================================================================
+// ErrorType
+//=============================================================================
+#[derive(Debug)]
+pub enum ErrorType
+{
+    Simple( &'static str ),
+    Custom( (&'static str, Box<dyn error::Error + Send + Sync>) ),
+}
+
+
+//=============================================================================
+// Error
+//
+// ハンドラから返すエラー。種類（エラーコード）ごとにステータスが決まり、
+// クライアントに応じてHTML、JSONまたはRFC 7807のproblem+jsonの
+// レスポンスになる。5xxのエラーは詳細をログにだけ出力し、
+// レスポンスには含めない。
+// ```
+// async fn show_user(Path(id): Path<u64>, db: Db) -> Result<Json<User>, ibis::Error>
+// {
+//      let user = find_user(&db, id)
+//          .await?
+//          .ok_or_else(|| ibis::Error::not_found(format!("user {} not found", id)))?;
+//      Ok(Json(user))
+// }
+// ```
+// ```
+// {"error":{"status":404,"code":"not_found","message":"user 42 not found"}}
+//
+// {"type":"about:blank","title":"Not Found","status":404,"detail":"user 42 not found",
+//  "instance":"/users/42","code":"not_found","request_id":"..."}
+// ```
+//=============================================================================
+pub struct Error
+{
+    error: ErrorType,
+    status: Option<StatusCode>,
+    problem_type: Option<String>,
+    members: Map<String, Value>,
+    backtrace: Option<Backtrace>,
+    expose: bool,
+}
+
+impl Error
+{
+    //=========================================================================
+    // カスタムエラー作成
+    //=========================================================================
+    pub fn new<E>(kind: &'static str, error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self
+        {
+            error: ErrorType::Custom((kind, error.into())),
+            status: None,
+            problem_type: None,
+            members: Map::new(),
+            backtrace: None,
+            expose: true,
+        }.capture_backtrace()
+    }
+
+    //=========================================================================
+    // 原因を持たないエラー作成
+    //=========================================================================
+    pub fn simple(kind: &'static str) -> Self
+    {
+        Self
+        {
+            error: ErrorType::Simple(kind),
+            status: None,
+            problem_type: None,
+            members: Map::new(),
+            backtrace: None,
+            expose: true,
+        }.capture_backtrace()
+    }
+
+    //=========================================================================
+    // 原因をログにだけ出力するエラー作成
+    //
+    // 4xxでもクライアントにはステータスの説明だけを返す。
+    //=========================================================================
+    pub(crate) fn private<E>(kind: &'static str, error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self { expose: false, ..Self::new(kind, error) }
+    }
+
+    //=========================================================================
+    // 400 Bad Request
+    //=========================================================================
+    pub fn bad_request<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("bad_request", error)
+    }
+
+    //=========================================================================
+    // 401 Unauthorized
+    //=========================================================================
+    pub fn unauthorized<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("unauthorized", error)
+    }
+
+    //=========================================================================
+    // 403 Forbidden
+    //=========================================================================
+    pub fn forbidden<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("forbidden", error)
+    }
+
+    //=========================================================================
+    // 404 Not Found
+    //=========================================================================
+    pub fn not_found<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("not_found", error)
+    }
+
+    //=========================================================================
+    // 409 Conflict
+    //=========================================================================
+    pub fn conflict<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("conflict", error)
+    }
+
+    //=========================================================================
+    // 422 Unprocessable Entity
+    //=========================================================================
+    pub fn unprocessable<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("unprocessable_entity", error)
+    }
+
+    //=========================================================================
+    // 500 Internal Server Error
+    //=========================================================================
+    pub fn internal<E>(error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new("internal_error", error)
+    }
+
+    //=========================================================================
+    // ステータスを指定（独自の種類のエラーは指定しなければ500になる）
+    //=========================================================================
+    pub fn with_status(mut self, status: StatusCode) -> Self
+    {
+        self.status = Some(status);
+        self.capture_backtrace()
+    }
+
+    //=========================================================================
+    // problem+jsonのtype（問題の種類を説明するURI）
+    //=========================================================================
+    pub fn with_type(mut self, uri: &str) -> Self
+    {
+        self.problem_type = Some(uri.to_string());
+        self
+    }
+
+    //=========================================================================
+    // problem+jsonに追加するメンバー
+    //=========================================================================
+    pub fn with_member<T>(mut self, name: &str, value: T) -> Self
+        where
+            T: Serialize,
+    {
+        match serde_json::to_value(value)
+        {
+            Ok(value) =>
+            {
+                self.members.insert(name.to_string(), value);
+            },
+            Err(e) => error!("failed to serialize problem member {}: {}", name, e),
+        }
+        self
+    }
+
+    //=========================================================================
+    // エラーの種類（機械的に判別するためのコード）
+    //=========================================================================
+    pub fn code(&self) -> &'static str
+    {
+        match &self.error
+        {
+            ErrorType::Simple(kind) => kind,
+            ErrorType::Custom((kind, _)) => kind,
+        }
+    }
+
+    //=========================================================================
+    // レスポンスのステータス
+    //=========================================================================
+    pub fn status(&self) -> StatusCode
+    {
+        self.status.unwrap_or_else(||
+        {
+            CODES.iter()
+                .find(|(_, code)| *code == self.code())
+                .map(|(status, _)| *status)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        })
+    }
+
+    //=========================================================================
+    // 開発環境の5xxのエラーでだけバックトレースを取得
+    //
+    // 404などの頻繁に発生するエラーでは取得しない。
+    //=========================================================================
+    fn capture_backtrace(mut self) -> Self
+    {
+        self.backtrace = match is_development() && self.status().is_server_error()
+        {
+            true => self.backtrace.or_else(|| Some(Backtrace::force_capture())),
+            false => None,
+        };
+        self
+    }
+
+    //=========================================================================
+    // ステータスから作成
+    //=========================================================================
+    pub(crate) fn from_status<E>(status: StatusCode, error: E) -> Self
+        where
+            E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Self::new(code_for(status), error).with_status(status)
+    }
+
+    //=========================================================================
+    // クライアントに返すメッセージ
+    //
+    // 4xxは原因のメッセージ、5xxと原因を公開しないエラーは内部の情報を
+    // 含めないようにステータスの説明だけを返す。
+    //=========================================================================
+    fn public_message(&self) -> String
+    {
+        let status = self.status();
+        match &self.error
+        {
+            ErrorType::Custom((_, source)) if self.expose && status.is_client_error() =>
+            {
+                source.to_string()
+            },
+            _ => status.canonical_reason().unwrap_or("Error").to_string(),
+        }
+    }
+
+    //=========================================================================
+    // 原因を含めたメッセージ（ログ用）
+    //=========================================================================
+    fn chain(&self) -> String
+    {
+        self.causes().join(": ")
+    }
+
+    //=========================================================================
+    // エラーとその原因のメッセージ
+    //=========================================================================
+    fn causes(&self) -> Vec<String>
+    {
+        let mut causes = vec![self.to_string()];
+        let mut source = error::Error::source(self);
+        while let Some(error) = source
+        {
+            causes.push(error.to_string());
+            source = error.source();
+        }
+        causes
+    }
+}
+
+//=============================================================================
+// Display実装
+//=============================================================================
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match &self.error
+        {
+            ErrorType::Simple(s) => f.write_str(s),
+            ErrorType::Custom(c) => f.write_str(c.0),
+        }
+    }
+}
+
+
+//=============================================================================
+// Debug実装
+//=============================================================================
+impl fmt::Debug for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        <Self as fmt::Display>::fmt(self, f)
+    }
+}
+
+//=============================================================================
+// Error実装
+//=============================================================================
+impl error::Error for Error
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)>
+    {
+        match &self.error
+        {
+            ErrorType::Simple(_) => None,
+            ErrorType::Custom((_, source)) => Some(source.as_ref()),
+        }
+    }
+}
+
+//=============================================================================
+// 他のエラーからの変換
+//=============================================================================
+impl From<Rejection> for Error
+{
+    fn from(rejection: Rejection) -> Self
+    {
+        Self::from_status(rejection.status(), rejection)
+    }
+}
+
+impl From<io::Error> for Error
+{
+    fn from(e: io::Error) -> Self
+    {
+        match e.kind()
+        {
+            io::ErrorKind::NotFound => Self::private("not_found", e),
+            _ => Self::internal(e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error
+{
+    fn from(e: sqlx::Error) -> Self
+    {
+        match e
+        {
+            sqlx::Error::RowNotFound => Self::private("not_found", e),
+            _ => Self::new("database_error", e),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error
+{
+    fn from(e: anyhow::Error) -> Self
+    {
+        Self::internal(e)
+    }
+}
+
+//=============================================================================
+// レスポンスへの変換
+//
+// ここではJSONで返し、HTMLを受け付けるクライアントにはlayer()で
+// HTMLに置き換える。
+//=============================================================================
+impl IntoResponse for Error
+{
+    fn into_response(self) -> Response
+    {
+        let report = self.report();
+        let mut response = report.render(Format::Json, &Instance::default());
+        response.extensions_mut().insert(report);
+        response
+    }
+}
+
+impl Error
+{
+    //=========================================================================
+    // ログに出力してレスポンスの内容に変換
+    //=========================================================================
+    fn report(self) -> ErrorReport
+    {
+        if self.status().is_server_error()
+        {
+            error!("{} ({})", self.chain(), self.status());
+        }
+        else if !self.expose
+        {
+            // クライアントに返さない原因はログで確認できるようにする
+            debug!("{} ({})", self.chain(), self.status());
+        }
+
+        // エラーの原因は開発環境でだけ保持する
+        let chain = match is_development()
+        {
+            true => self.causes(),
+            false => Vec::new(),
+        };
+
+        ErrorReport
+        {
+            status: self.status(),
+            code: self.code(),
+            message: self.public_message(),
+            problem_type: self.problem_type,
+            members: self.members,
+            chain,
+            backtrace: self.backtrace.map(|backtrace| backtrace.to_string()),
+        }
+    }
+}
+
+
+//=============================================================================
+// ErrorReport
+//
+// レスポンスに変換したエラーの内容（layer()で形式を選び直すために保持する）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub(crate) struct ErrorReport
+{
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    problem_type: Option<String>,
+    members: Map<String, Value>,
+
+    // 以下は開発環境でだけ値を持つ
+    chain: Vec<String>,
+    backtrace: Option<String>,
+}
+
+impl ErrorReport
+{
+    //=========================================================================
+    // ステータスだけのレスポンスから作成
+    //=========================================================================
+    fn from_status(status: StatusCode, message: Option<String>) -> Self
+    {
+        // 5xxの本文は内部の情報を含みうるので、開発環境の詳細にだけ使う
+        let chain = match is_development()
+        {
+            true => message.iter().cloned().collect(),
+            false => Vec::new(),
+        };
+        let message = match message
+        {
+            Some(message) if status.is_client_error() => message,
+            _ => status.canonical_reason().unwrap_or("Error").to_string(),
+        };
+
+        Self
+        {
+            status,
+            code: code_for(status),
+            message,
+            problem_type: None,
+            members: Map::new(),
+            chain,
+            backtrace: None,
+        }
+    }
+
+    //=========================================================================
+    // 指定の形式でレスポンスを作成
+    //=========================================================================
+    fn render(&self, format: Format, instance: &Instance) -> Response
+    {
+        let (body, content_type) = match format
+        {
+            Format::Json => (self.json(), "application/json"),
+            Format::Problem => (self.problem(instance), "application/problem+json"),
+            Format::Html => (self.html(), "text/html; charset=utf-8"),
+        };
+
+        self.response(body, content_type)
+    }
+
+    //=========================================================================
+    // 本文とContent-Typeからレスポンスを作成
+    //=========================================================================
+    fn response(&self, body: String, content_type: &'static str) -> Response
+    {
+        let mut response = Response::new(boxed(Full::from(body)));
+        *response.status_mut() = self.status;
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(content_type)
+        );
+        response
+    }
+
+    //=========================================================================
+    // ステータスの説明（"404 Not Found"）
+    //=========================================================================
+    fn title(&self) -> String
+    {
+        format!(
+            "{} {}",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or("Error")
+        )
+    }
+
+    //=========================================================================
+    // JSONの本文
+    //=========================================================================
+    fn json(&self) -> String
+    {
+        serde_json::json!({
+            "error":
+            {
+                "status": self.status.as_u16(),
+                "code": self.code,
+                "message": self.message,
+            }
+        }).to_string()
+    }
+
+    //=========================================================================
+    // RFC 7807のproblem+jsonの本文
+    //
+    // typeを指定していなければabout:blankとし、titleはステータスの説明にする。
+    //=========================================================================
+    fn problem(&self, instance: &Instance) -> String
+    {
+        let mut problem = Map::new();
+        problem.insert(
+            "type".to_string(),
+            Value::from(self.problem_type.as_deref().unwrap_or("about:blank"))
+        );
+        problem.insert(
+            "title".to_string(),
+            Value::from(self.status.canonical_reason().unwrap_or("Error"))
+        );
+        problem.insert("status".to_string(), Value::from(self.status.as_u16()));
+        problem.insert("detail".to_string(), Value::from(self.message.as_str()));
+        if let Some(path) = &instance.path
+        {
+            problem.insert("instance".to_string(), Value::from(path.as_str()));
+        }
+        problem.insert("code".to_string(), Value::from(self.code));
+        if let Some(request_id) = &instance.request_id
+        {
+            problem.insert("request_id".to_string(), Value::from(request_id.as_str()));
+        }
+
+        // 標準のメンバーは上書きしない
+        for (name, value) in &self.members
+        {
+            problem.entry(name.as_str()).or_insert_with(|| value.clone());
+        }
+
+        Value::Object(problem).to_string()
+    }
+
+    //=========================================================================
+    // HTMLの本文
+    //=========================================================================
+    fn html(&self) -> String
+    {
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+             <body>\n<h1>{0}</h1>\n<p>{1}</p>\n</body>\n</html>\n",
+            escape_html(&self.title()),
+            escape_html(&self.message),
+        )
+    }
+
+    //=========================================================================
+    // 開発環境で表示するデバッグページ
+    //
+    // エラーの原因、リクエストの内容、バックトレースを表示する。
+    //=========================================================================
+    fn debug_html(&self, request: &RequestDetails) -> String
+    {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title>\n\
+             <style>\n\
+             body {{ font-family: sans-serif; margin: 2em; color: #222; }}\n\
+             h1 {{ color: #b00020; }}\n\
+             th {{ text-align: left; padding-right: 1em; vertical-align: top; }}\n\
+             pre {{ background: #f4f4f4; padding: 1em; overflow: auto; }}\n\
+             </style>\n</head>\n<body>\n<h1>{0}</h1>\n<p>{1}: {2}</p>\n",
+            escape_html(&self.title()),
+            escape_html(self.code),
+            escape_html(&self.message),
+        );
+
+        html.push_str("<h2>Error chain</h2>\n<ol>\n");
+        for cause in &self.chain
+        {
+            html.push_str(&format!("<li>{}</li>\n", escape_html(cause)));
+        }
+        html.push_str("</ol>\n");
+
+        html.push_str("<h2>Request</h2>\n<table>\n");
+        let rows = [
+            ("Method", request.method.as_str()),
+            ("URI", request.uri.as_str()),
+            ("Version", request.version.as_str()),
+            ("Peer", request.peer.as_deref().unwrap_or("-")),
+            ("Request ID", request.request_id.as_deref().unwrap_or("-")),
+        ];
+        let headers = request.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        for (name, value) in rows.into_iter().chain(headers)
+        {
+            html.push_str(&format!(
+                "<tr><th>{}</th><td>{}</td></tr>\n",
+                escape_html(name),
+                escape_html(value)
+            ));
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Backtrace</h2>\n<pre>");
+        html.push_str(&escape_html(self.backtrace.as_deref().unwrap_or("(not captured)")));
+        html.push_str("</pre>\n</body>\n</html>\n");
+        html
+    }
+}
+
+
+//=============================================================================
+// Format
+//
+// エラーのレスポンスの形式
+//=============================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format
+{
+    Json,
+    Problem,
+    Html,
+}
+
+impl Format
+{
+    //=========================================================================
+    // Acceptヘッダから選ぶ
+    //
+    // problem+jsonを明示したクライアントにはproblem+json、ブラウザにはHTML、
+    // それ以外にはJSONを返す。
+    //=========================================================================
+    fn negotiate(headers: &HeaderMap) -> Self
+    {
+        Self::from_accept(headers.get(header::ACCEPT))
+    }
+
+    //=========================================================================
+    // Acceptヘッダの値から選ぶ
+    //=========================================================================
+    fn from_accept(accept: Option<&HeaderValue>) -> Self
+    {
+        let accept = accept
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or("");
+
+        let problem = quality(accept, "application/problem+json").unwrap_or(0.0);
+        let html = quality(accept, "text/html").unwrap_or(0.0);
+        let json = quality(accept, "application/json").unwrap_or(0.0);
+        if problem > 0.0 && problem >= html && problem >= json
+        {
+            return Self::Problem;
+        }
+        match html > 0.0 && html >= json
+        {
+            true => Self::Html,
+            false => Self::Json,
+        }
+    }
+}
+
+
+//=============================================================================
+// Instance
+//
+// エラーが発生したリクエスト
+//=============================================================================
+#[derive(Debug, Default)]
+struct Instance
+{
+    path: Option<String>,
+    request_id: Option<String>,
+}
+
+
+//=============================================================================
+// ErrorTarget
+//
+// layer()を通らずにサーバが直接返すエラー（タイムアウトやボディのサイズ
+// 超過など）の返し先。ハンドラのエラーと同じくAcceptヘッダで形式を選び、
+// problem+jsonを返すグループの下のパスにはproblem+jsonで返す。
+// HTMLはエラーページのテンプレートを使わず、組み込みのページを返す。
+//=============================================================================
+#[derive(Debug)]
+pub(crate) struct ErrorTarget
+{
+    format: Format,
+    instance: Instance,
+}
+
+impl ErrorTarget
+{
+    //=========================================================================
+    // リクエストのAcceptヘッダとパスから作成（リクエストを読めなかった場合はNone）
+    //=========================================================================
+    pub(crate) fn new(accept: Option<&HeaderValue>, path: Option<&str>) -> Self
+    {
+        let in_group = path.is_some_and(|path|
+        {
+            PROBLEM_PREFIXES.read()
+                .map(|prefixes| prefixes.iter().any(|prefix| is_under(path, prefix)))
+                .unwrap_or(false)
+        });
+        let format = match in_group
+        {
+            true => Format::Problem,
+            false => Format::from_accept(accept),
+        };
+
+        Self
+        {
+            format,
+            instance: Instance
+            {
+                path: path.map(|path| path.to_string()),
+                request_id: None,
+            },
+        }
+    }
+
+    //=========================================================================
+    // エラーのレスポンスを作成
+    //=========================================================================
+    pub(crate) fn response(&self, error: Error) -> Response
+    {
+        error.report().render(self.format, &self.instance)
+    }
+}
+
+
+//=============================================================================
+// RequestDetails
+//
+// デバッグページに表示するリクエストの内容（開発環境でだけ取得する）
+//=============================================================================
+#[derive(Debug)]
+struct RequestDetails
+{
+    method: String,
+    uri: String,
+    version: String,
+    peer: Option<String>,
+    request_id: Option<String>,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestDetails
+{
+    //=========================================================================
+    // リクエストから作成（認証情報を含むヘッダとクエリパラメータの値は伏せる）
+    //=========================================================================
+    fn new(request: &Request<Body>) -> Self
+    {
+        Self
+        {
+            method: request.method().to_string(),
+            uri: redact_query(request.uri()),
+            version: format!("{:?}", request.version()),
+            peer: request.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| peer.to_string()),
+            request_id: request.extensions()
+                .get::<RequestId>()
+                .map(|request_id| request_id.to_string()),
+            headers: request.headers()
+                .iter()
+                .map(|(name, value)|
+                {
+                    let value = match REDACTED_HEADERS.contains(name) || is_sensitive(name.as_str())
+                    {
+                        true => REDACTED.to_string(),
+                        false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+        }
+    }
+}
+
+
+//=============================================================================
+// 認証情報を含みそうな名前か（大文字小文字は区別しない）
+//=============================================================================
+fn is_sensitive(name: &str) -> bool
+{
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_NAMES.iter().any(|word| name.contains(word))
+}
+
+
+//=============================================================================
+// URIのクエリのうち認証情報を含みそうなパラメータの値を伏せる
+//
+// /login?user=a&password=b -> /login?user=a&password=[redacted]
+//=============================================================================
+fn redact_query(uri: &Uri) -> String
+{
+    let uri = uri.to_string();
+    let (base, query) = match uri.split_once('?')
+    {
+        Some(split) => split,
+        None => return uri,
+    };
+
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=')
+        {
+            Some((name, _)) if is_sensitive(name) => format!("{}={}", name, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", base, query)
+}
+
+
+//=============================================================================
+// ErrorPage
+//
+// エラーページのテンプレートに渡す値。App::error_page()で登録した
+// テンプレートでは`{{ error.status }}`のように参照する。
+// ```
+// #[derive(askama::Template)]
+// #[template(path = "errors/404.html")]
+// struct NotFoundTemplate
+// {
+//      error: ibis::ErrorPage,
+// }
+//
+// ibis::App::new()
+//      .error_page(StatusCode::NOT_FOUND, |error| NotFoundTemplate { error })
+//      .run();
+// ```
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct ErrorPage
+{
+    pub status: u16,
+    pub title: String,
+    pub code: String,
+    pub message: String,
+    pub path: String,
+    pub request_id: Option<String>,
+}
+
+impl ErrorPage
+{
+    //=========================================================================
+    // レスポンスにしたエラーから作成
+    //=========================================================================
+    fn new(report: &ErrorReport, instance: &Instance) -> Self
+    {
+        Self
+        {
+            status: report.status.as_u16(),
+            title: report.status.canonical_reason().unwrap_or("Error").to_string(),
+            code: report.code.to_string(),
+            message: report.message.clone(),
+            path: instance.path.clone().unwrap_or_default(),
+            request_id: instance.request_id.clone(),
+        }
+    }
+}
+
+
+//=============================================================================
+// ErrorPages
+//
+// ステータスごとのエラーページのテンプレート
+//=============================================================================
+type RenderPage = Arc<dyn Fn(ErrorPage) -> askama::Result<String> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct ErrorPages
+{
+    pages: HashMap<u16, RenderPage>,
+}
+
+impl ErrorPages
+{
+    //=========================================================================
+    // テンプレートを登録
+    //=========================================================================
+    pub(crate) fn add<T, F>(&mut self, status: StatusCode, page: F)
+        where
+            T: Template,
+            F: Fn(ErrorPage) -> T + Send + Sync + 'static,
+    {
+        self.pages.insert(status.as_u16(), Arc::new(move |error| page(error).render()));
+    }
+
+    //=========================================================================
+    // エラーページをレンダリング
+    //
+    // ステータスのテンプレートがなければ、5xxは500のテンプレートを使う。
+    // テンプレートがないかレンダリングに失敗したらNoneを返す。
+    //=========================================================================
+    fn render(&self, page: ErrorPage) -> Option<String>
+    {
+        let status = page.status;
+        let render = self.pages.get(&status).or_else(||
+        {
+            (500..600).contains(&status)
+                .then(|| self.pages.get(&500))
+                .flatten()
+        })?;
+
+        match render(page)
+        {
+            Ok(html) => Some(html),
+            Err(e) =>
+            {
+                error!("failed to render error page for {}: {}", status, e);
+                None
+            },
+        }
+    }
+}
+
+
+//=============================================================================
+// Acceptヘッダでのメディアタイプの優先度（明示されていなければNone）
+//=============================================================================
+pub(crate) fn quality(accept: &str, media_type: &str) -> Option<f32>
+{
+    accept.split(',').find_map(|range|
+    {
+        let mut params = range.split(';').map(str::trim);
+        if !params.next()?.eq_ignore_ascii_case(media_type)
+        {
+            return None;
+        }
+
+        let q = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        Some(q)
+    })
+}
+
+
+//=============================================================================
+// HTMLのエスケープ
+//=============================================================================
+pub(crate) fn escape_html(value: &str) -> String
+{
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars()
+    {
+        match c
+        {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+
+//=============================================================================
+// エラーのレスポンスをクライアントに応じた形式にするLayer
+//
+// ハンドラが返したErrorに加えて、ルートが見つからない場合の404や405など
+// フレームワークが目印を付けて返したエラーも同じ形式にする。
+// ハンドラが自分で組み立てたレスポンス（(StatusCode, &str)など）はそのまま返す。
+// ハンドラがパニックした場合は500を返す。
+//
+// HTMLは次の順に選ぶ。
+// (1) 開発環境の5xx: エラーの原因、リクエスト、バックトレースを含むデバッグページ
+// (2) App::error_page()で登録したテンプレート
+// (3) 組み込みの簡単なページ
+//=============================================================================
+pub(crate) fn layer(pages: ErrorPages) -> MiddlewareLayer
+{
+    middleware::layer(ErrorRenderer { pages })
+}
+
+
+//=============================================================================
+// 常にproblem+jsonで返すLayer（Group::problem_details()で使う）
+//=============================================================================
+pub(crate) fn problem_details_layer() -> MiddlewareLayer
+{
+    middleware::layer(ProblemDetails)
+}
+
+
+//=============================================================================
+// レスポンスをproblem+jsonで返すように目印を付ける
+//=============================================================================
+pub(crate) fn problem_details(mut response: Response) -> Response
+{
+    response.extensions_mut().insert(ProblemDetails);
+    response
+}
+
+
+//=============================================================================
+// フレームワークが返したエラーのレスポンスに目印を付ける
+//
+// ErrorRendererは目印の付いたレスポンスだけを、本文をメッセージとして
+// クライアントに応じた形式に置き換える。
+//=============================================================================
+pub(crate) fn framework_error(mut response: Response) -> Response
+{
+    response.extensions_mut().insert(FrameworkError);
+    response
+}
+
+
+//=============================================================================
+// FrameworkError
+//
+// フレームワークが返したエラーのレスポンスの目印
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+struct FrameworkError;
+
+
+//=============================================================================
+// ProblemDetails
+//
+// レスポンスに目印を付けて、ErrorRendererにproblem+jsonを選ばせる
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+struct ProblemDetails;
+
+#[async_trait]
+impl Middleware for ProblemDetails
+{
+    async fn handle(&self, request: Request<Body>, next: Next) -> Response
+    {
+        problem_details(next.run(request).await)
+    }
+}
+
+
+//=============================================================================
+// ErrorRenderer
+//=============================================================================
+struct ErrorRenderer
+{
+    pages: ErrorPages,
+}
+
+impl ErrorRenderer
+{
+    //=========================================================================
+    // HTMLのレスポンスを作成
+    //=========================================================================
+    fn render_html(
+        &self,
+        report: &ErrorReport,
+        instance: &Instance,
+        request: Option<&RequestDetails>,
+    ) -> Response
+    {
+        if let Some(request) = request.filter(|_| report.status.is_server_error())
+        {
+            return report.response(report.debug_html(request), "text/html; charset=utf-8");
+        }
+
+        match self.pages.render(ErrorPage::new(report, instance))
+        {
+            Some(html) => report.response(html, "text/html; charset=utf-8"),
+            None => report.render(Format::Html, instance),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for ErrorRenderer
+{
+    async fn handle(&self, request: Request<Body>, next: Next) -> Response
+    {
+        let format = Format::negotiate(request.headers());
+        let instance = Instance
+        {
+            path: Some(request.uri().path().to_string()),
+            request_id: request.extensions()
+                .get::<RequestId>()
+                .map(|request_id| request_id.to_string()),
+        };
+        let details = is_development().then(|| RequestDetails::new(&request));
+
+        // パニックしたハンドラは500のエラーにする
+        let response = match AssertUnwindSafe(next.run(request)).catch_unwind().await
+        {
+            Ok(response) => response,
+            Err(payload) =>
+            {
+                let mut error = Error::internal(format!(
+                    "handler panicked: {}",
+                    panic_message(payload.as_ref())
+                ));
+                if let Some(backtrace) = PANIC_BACKTRACE.with(|backtrace| backtrace.borrow_mut().take())
+                {
+                    error.backtrace = Some(backtrace);
+                }
+                error.into_response()
+            },
+        };
+        let format = match response.extensions().get::<ProblemDetails>()
+        {
+            Some(_) => Format::Problem,
+            None => format,
+        };
+
+        let (mut parts, body) = response.into_parts();
+        let report = match parts.extensions.get::<ErrorReport>()
+        {
+            Some(_) if format == Format::Json => return Response::from_parts(parts, body),
+            Some(report) => report.clone(),
+            None => match plain_error(&parts, &body)
+            {
+                true =>
+                {
+                    let message = hyper::body::to_bytes(body)
+                        .await
+                        .ok()
+                        .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+                        .filter(|message| !message.trim().is_empty());
+                    ErrorReport::from_status(parts.status, message)
+                },
+                false => return Response::from_parts(parts, body),
+            },
+        };
+
+        // ステータス以外のヘッダ（Allow等）は残して本文を置き換える
+        let rendered = match format
+        {
+            Format::Html => self.render_html(&report, &instance, details.as_ref()),
+            _ => report.render(format, &instance),
+        };
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.extend(rendered.headers().clone());
+        parts.extensions.insert(report);
+
+        Response::from_parts(parts, rendered.into_body())
+    }
+}
+
+
+//=============================================================================
+// パニックのメッセージ
+//=============================================================================
+fn panic_message(payload: &(dyn Any + Send)) -> String
+{
+    payload.downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
+
+
+//=============================================================================
+// フレームワークが返した、本文が空または短いテキストのエラーのレスポンスか
+//=============================================================================
+fn plain_error(parts: &Parts, body: &BoxBody) -> bool
+{
+    let is_framework = parts.extensions.get::<FrameworkError>().is_some();
+    let is_error = parts.status.is_client_error() || parts.status.is_server_error();
+    let is_text = parts.headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_none_or(|content_type| content_type.starts_with("text/plain"));
+    let is_short = body.size_hint()
+        .exact()
+        .is_some_and(|size| size <= MAX_PLAIN_ERROR_SIZE);
+
+    is_framework && is_error && is_text && is_short
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use crate::router::IbisRouter;
+    use crate::shutdown::ShutdownSignal;
+
+    use axum::http::Method;
+    use axum::routing::MethodFilter;
+
+    use tower::ServiceExt;
+
+
+    //=========================================================================
+    // エラーのLayerを通してリクエストを1つ処理する
+    //=========================================================================
+    async fn render(method: Method, path: &str) -> (StatusCode, HeaderMap, String)
+    {
+        let mut router = IbisRouter::new();
+        router.add(MethodFilter::GET, "/csv", || async
+        {
+            (StatusCode::BAD_REQUEST, "bad csv row 3")
+        });
+        router.add(MethodFilter::GET, "/gone", || async { StatusCode::GONE });
+        router.add(MethodFilter::GET, "/shutdown", |_: ShutdownSignal| async { "ok" });
+        router.layer(layer(ErrorPages::default()));
+
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.into_router().oneshot(request).await.unwrap();
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (parts.status, parts.headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+
+    //=========================================================================
+    // 認証情報を含むヘッダとクエリパラメータの値を伏せる
+    //=========================================================================
+    #[test]
+    fn request_details_redact_credentials()
+    {
+        let request = Request::builder()
+            .uri("/login?user=alice&password=hunter2&access_token=abc&page=2&flag")
+            .header(header::AUTHORIZATION, "Bearer abc")
+            .header(header::PROXY_AUTHORIZATION, "Basic abc")
+            .header(header::COOKIE, "session=abc")
+            .header(header::SET_COOKIE, "session=abc")
+            .header("X-Api-Key", "abc")
+            .header("X-Auth-Token", "abc")
+            .header("X-Client-Secret", "abc")
+            .header(header::ACCEPT, "text/html")
+            .header(header::USER_AGENT, "curl")
+            .body(Body::empty())
+            .unwrap();
+
+        let details = RequestDetails::new(&request);
+        assert_eq!(
+            details.uri,
+            "/login?user=alice&password=[redacted]&access_token=[redacted]&page=2&flag"
+        );
+
+        let visible = details.headers.iter()
+            .filter(|(_, value)| value != REDACTED)
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(visible, ["accept", "user-agent"]);
+        assert!(details.headers.iter().all(|(_, value)| !value.contains("abc")));
+    }
+
+    //=========================================================================
+    // クエリのないURIはそのまま表示する
+    //=========================================================================
+    #[test]
+    fn redact_query_without_query()
+    {
+        assert_eq!(redact_query(&Uri::from_static("/users/1")), "/users/1");
+        assert_eq!(
+            redact_query(&Uri::from_static("http://example.com/?API_KEY=abc")),
+            "http://example.com/?API_KEY=[redacted]"
+        );
+    }
+
+    //=========================================================================
+    // ハンドラが組み立てたテキストのエラーはそのまま返す
+    //=========================================================================
+    #[tokio::test]
+    async fn handler_plain_text_is_kept()
+    {
+        let (status, headers, body) = render(Method::GET, "/csv").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(body, "bad csv row 3");
+
+        let (status, _, body) = render(Method::GET, "/gone").await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body, "");
+    }
+
+    //=========================================================================
+    // フレームワークが返した404、405、取り出しの失敗はエラーの形式にする
+    //=========================================================================
+    #[tokio::test]
+    async fn framework_errors_are_rendered()
+    {
+        let (status, headers, body) = render(Method::GET, "/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert!(body.contains("not_found"), "{}", body);
+
+        // Allowヘッダは残す
+        let (status, headers, body) = render(Method::POST, "/csv").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[header::ALLOW], "GET,HEAD");
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert!(body.contains("method_not_allowed"), "{}", body);
+
+        let (status, headers, _) = render(Method::GET, "/shutdown").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+    }
+}
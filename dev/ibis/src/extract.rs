@@ -0,0 +1,688 @@
+use crate::middleware::{ self, Middleware, MiddlewareLayer, Next };
+
+use std::any::type_name;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+
+use axum::async_trait;
+use axum::body::{ Body, Bytes, HttpBody };
+use axum::extract::{ ConnectInfo, FromRequest, RequestParts };
+use axum::extract::rejection::QueryRejection;
+use axum::http::header::{ self, HeaderName };
+use axum::http::{ HeaderMap, Request, StatusCode };
+use axum::response::{ IntoResponse, Response };
+use axum::BoxError;
+
+use futures_util::StreamExt;
+
+use serde::de::DeserializeOwned;
+
+
+//=============================================================================
+// ハンドラの引数として使う値の取り出し
+//
+// 取り出しに失敗すると、原因に応じたステータスとメッセージのレスポンスを返す。
+// (1) パスやクエリ文字列、ヘッダの形式が正しくない: 400
+// (2) Content-Typeがボディの型と一致しない: 415
+// (3) ボディの形式は正しいが、型に変換できない: 422
+// ```
+// #[derive(Deserialize)]
+// struct Paging
+// {
+//      page: u32,
+// }
+//
+// #[derive(Deserialize)]
+// struct NewComment
+// {
+//      body: String,
+// }
+//
+// async fn add_comment(
+//      Path(id): Path<u64>,
+//      Query(paging): Query<Paging>,
+//      TypedHeader(agent): TypedHeader<UserAgent>,
+//      cookies: Cookies,
+//      Json(comment): Json<NewComment>,
+// ) -> String
+// {
+//      format!("{} {} {} {:?} {}", id, paging.page, agent.0, cookies.get("session"), comment.body)
+// }
+// ```
+//=============================================================================
+
+
+//=============================================================================
+// Rejection
+//
+// 取り出しに失敗したときのレスポンス
+//=============================================================================
+#[derive(Debug)]
+pub struct Rejection
+{
+    status: StatusCode,
+    message: String,
+}
+
+impl Rejection
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub fn new<T>(status: StatusCode, message: T) -> Self
+        where
+            T: Into<String>,
+    {
+        Self { status, message: message.into() }
+    }
+
+    //=========================================================================
+    // レスポンスのステータス
+    //=========================================================================
+    pub fn status(&self) -> StatusCode
+    {
+        self.status
+    }
+
+    //=========================================================================
+    // 失敗の理由
+    //=========================================================================
+    pub fn message(&self) -> &str
+    {
+        &self.message
+    }
+
+    //=========================================================================
+    // axumのRejectionから作成
+    //
+    // axumのRejectionはDisplayに原因を含まないため、source()を辿って
+    // メッセージに付け加える。
+    //=========================================================================
+    fn from_axum<R>(rejection: R) -> Self
+        where
+            R: IntoResponse + Error,
+    {
+        let mut message = rejection.to_string();
+        let mut source = rejection.source();
+        while let Some(error) = source
+        {
+            let cause = error.to_string();
+            if !message.ends_with(&cause)
+            {
+                message = format!("{}: {}", message, cause);
+            }
+            source = error.source();
+        }
+
+        Self
+        {
+            status: rejection.into_response().status(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Rejection
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Rejection {}
+
+impl IntoResponse for Rejection
+{
+    fn into_response(self) -> Response
+    {
+        crate::Error::from(self).into_response()
+    }
+}
+
+
+//=============================================================================
+// Path
+//
+// パスパラメータ（`/users/:id`の`:id`）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<Body> for Path<T>
+    where
+        T: DeserializeOwned + Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection>
+    {
+        axum::extract::Path::<T>::from_request(req)
+            .await
+            .map(|axum::extract::Path(value)| Self(value))
+            .map_err(Rejection::from_axum)
+    }
+}
+
+
+//=============================================================================
+// Query
+//
+// クエリ文字列
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<Body> for Query<T>
+    where
+        T: DeserializeOwned + Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection>
+    {
+        match axum::extract::Query::<T>::from_request(req).await
+        {
+            Ok(axum::extract::Query(value)) => Ok(Self(value)),
+
+            // axumは422を返すが、URLの誤りなので400とする
+            Err(rejection @ QueryRejection::FailedToDeserializeQueryString(_)) =>
+            {
+                Err(Rejection::new(StatusCode::BAD_REQUEST, rejection.to_string()))
+            },
+            Err(rejection) => Err(Rejection::from_axum(rejection)),
+        }
+    }
+}
+
+
+//=============================================================================
+// BodyLimit
+//
+// リクエストボディの最大サイズ。ibisのHTTP/1.1の実装のようにサーバが
+// 上限を確認したリクエストには、サーバがextensionsに追加する。
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BodyLimit(pub(crate) u64);
+
+
+//=============================================================================
+// BodyTooLarge
+//
+// ボディが上限を超えた（制限したBodyを読み込むとこのエラーになる）
+//=============================================================================
+#[derive(Debug)]
+pub(crate) struct BodyTooLarge(u64);
+
+impl fmt::Display for BodyTooLarge
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "request body exceeds {} bytes", self.0)
+    }
+}
+
+impl Error for BodyTooLarge {}
+
+
+//=============================================================================
+// リクエストボディのサイズを制限するLayer（max_body_sizeが0以外のとき）
+//
+// Content-Lengthが上限を超えていれば413を返し、長さの分からないボディは
+// 上限を超えたところで読み込みをエラーにする。JsonやFormの抽出子は
+// そのエラーを413にする。サーバが上限を確認済みのリクエストはそのまま渡す。
+//=============================================================================
+pub(crate) fn body_limit_layer(limit: u64) -> MiddlewareLayer
+{
+    middleware::layer(RequestBodyLimit { limit })
+}
+
+
+//=============================================================================
+// RequestBodyLimit
+//=============================================================================
+struct RequestBodyLimit
+{
+    limit: u64,
+}
+
+#[async_trait]
+impl Middleware for RequestBodyLimit
+{
+    async fn handle(&self, mut request: Request<Body>, next: Next) -> Response
+    {
+        let limit = self.limit;
+        if content_length(request.headers()).is_some_and(|length| length > limit)
+        {
+            return crate::Error::from_status(StatusCode::PAYLOAD_TOO_LARGE, BodyTooLarge(limit))
+                .into_response();
+        }
+
+        // Content-Lengthのあるボディはサーバが長さを保証する
+        let checked = request.extensions().get::<BodyLimit>().is_some();
+        if !checked && content_length(request.headers()).is_none()
+        {
+            let body = std::mem::take(request.body_mut());
+            *request.body_mut() = limit_stream(body, limit);
+        }
+
+        request.extensions_mut().insert(BodyLimit(limit));
+        next.run(request).await
+    }
+}
+
+
+//=============================================================================
+// 上限を超えたところでエラーになるボディ
+//=============================================================================
+fn limit_stream(body: Body, limit: u64) -> Body
+{
+    let mut total: u64 = 0;
+    Body::wrap_stream(body.map(move |chunk| -> Result<Bytes, BoxError>
+    {
+        let chunk = chunk?;
+        total = total.saturating_add(chunk.len() as u64);
+        match total > limit
+        {
+            true => Err(BodyTooLarge(limit).into()),
+            false => Ok(chunk),
+        }
+    }))
+}
+
+
+//=============================================================================
+// Content-Lengthの値
+//=============================================================================
+fn content_length(headers: &HeaderMap) -> Option<u64>
+{
+    headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+}
+
+
+//=============================================================================
+// ボディを上限まで読み込み、超えた場合は413を返す
+//
+// axumの抽出子はボディをすべてメモリに読み込むため、その前に呼び出す。
+//=============================================================================
+async fn limit_body(req: &mut RequestParts<Body>) -> Result<(), Rejection>
+{
+    let limit = match req.extensions().get::<BodyLimit>()
+    {
+        Some(BodyLimit(limit)) => *limit,
+        None => return Ok(()),
+    };
+    let too_large = || Rejection::new(StatusCode::PAYLOAD_TOO_LARGE, BodyTooLarge(limit).to_string());
+
+    if content_length(req.headers()).is_some_and(|length| length > limit)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = match req.body_mut().as_mut()
+    {
+        Some(body) => std::mem::take(body),
+        None => return Ok(()),
+    };
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await
+    {
+        let chunk = match chunk
+        {
+            Ok(chunk) => chunk,
+            Err(e) if is_too_large(&e) => return Err(too_large()),
+            Err(e) => return Err(Rejection::new(StatusCode::BAD_REQUEST, e.to_string())),
+        };
+        if (buf.len() + chunk.len()) as u64 > limit
+        {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    *req.body_mut() = Some(Body::from(buf));
+    Ok(())
+}
+
+
+//=============================================================================
+// ボディの読み込みエラーが上限の超過によるものか
+//=============================================================================
+fn is_too_large(error: &(dyn Error + 'static)) -> bool
+{
+    let mut source = Some(error);
+    while let Some(error) = source
+    {
+        if error.is::<BodyTooLarge>()
+        {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+
+//=============================================================================
+// Json
+//
+// application/jsonのボディ（レスポンスとして返すこともできる）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<Body> for Json<T>
+    where
+        T: DeserializeOwned + Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection>
+    {
+        limit_body(req).await?;
+        axum::Json::<T>::from_request(req)
+            .await
+            .map(|axum::Json(value)| Self(value))
+            .map_err(Rejection::from_axum)
+    }
+}
+
+
+//=============================================================================
+// Form
+//
+// application/x-www-form-urlencodedのボディ
+// （GETやHEADではクエリ文字列から取り出す）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Form<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<Body> for Form<T>
+    where
+        T: DeserializeOwned + Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection>
+    {
+        limit_body(req).await?;
+        axum::extract::Form::<T>::from_request(req)
+            .await
+            .map(|axum::extract::Form(value)| Self(value))
+            .map_err(Rejection::from_axum)
+    }
+}
+
+
+//=============================================================================
+// Header
+//
+// TypedHeaderで取り出すヘッダの型
+// ```
+// struct ApiKey(String);
+//
+// impl Header for ApiKey
+// {
+//      fn name() -> HeaderName
+//      {
+//          HeaderName::from_static("x-api-key")
+//      }
+//
+//      fn decode(value: &str) -> Option<Self>
+//      {
+//          Some(Self(value.to_string()))
+//      }
+// }
+// ```
+//=============================================================================
+pub trait Header: Sized
+{
+    fn name() -> HeaderName;
+    fn decode(value: &str) -> Option<Self>;
+}
+
+
+//=============================================================================
+// TypedHeader
+//
+// ヘッダの値（ヘッダがなければ400、省略できるならOption<TypedHeader<H>>）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct TypedHeader<H>(pub H);
+
+#[async_trait]
+impl<H, B> FromRequest<B> for TypedHeader<H>
+    where
+        H: Header + Send,
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        let name = H::name();
+        let value = req.headers().get(&name).ok_or_else(|| Rejection::new(
+            StatusCode::BAD_REQUEST,
+            format!("Missing request header `{}`", name),
+        ))?;
+
+        value.to_str()
+            .ok()
+            .and_then(H::decode)
+            .map(Self)
+            .ok_or_else(|| Rejection::new(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid request header `{}`", name),
+            ))
+    }
+}
+
+//=============================================================================
+// 文字列をそのまま保持するヘッダの定義
+//=============================================================================
+macro_rules! string_header
+{
+    ($(#[$doc:meta])* $name:ident, $header:expr) =>
+    {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name(pub String);
+
+        impl Header for $name
+        {
+            fn name() -> HeaderName
+            {
+                $header
+            }
+
+            fn decode(value: &str) -> Option<Self>
+            {
+                Some(Self(value.to_string()))
+            }
+        }
+    };
+}
+
+string_header!(Accept, header::ACCEPT);
+string_header!(Host, header::HOST);
+string_header!(UserAgent, header::USER_AGENT);
+
+//=============================================================================
+// ContentType
+//
+// パラメータ（`; charset=utf-8`等）を除いたメディアタイプ
+//=============================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType(pub String);
+
+impl Header for ContentType
+{
+    fn name() -> HeaderName
+    {
+        header::CONTENT_TYPE
+    }
+
+    fn decode(value: &str) -> Option<Self>
+    {
+        let media_type = value.split(';').next()?.trim();
+        (!media_type.is_empty()).then(|| Self(media_type.to_ascii_lowercase()))
+    }
+}
+
+//=============================================================================
+// Authorization
+//=============================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorization(pub String);
+
+impl Authorization
+{
+    //=========================================================================
+    // Bearerトークン
+    //=========================================================================
+    pub fn bearer(&self) -> Option<&str>
+    {
+        let (scheme, token) = self.0.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    }
+}
+
+impl Header for Authorization
+{
+    fn name() -> HeaderName
+    {
+        header::AUTHORIZATION
+    }
+
+    fn decode(value: &str) -> Option<Self>
+    {
+        Some(Self(value.to_string()))
+    }
+}
+
+
+//=============================================================================
+// Cookies
+//
+// Cookieヘッダの名前と値の組
+//=============================================================================
+#[derive(Debug, Clone, Default)]
+pub struct Cookies
+{
+    cookies: Vec<(String, String)>,
+}
+
+impl Cookies
+{
+    //=========================================================================
+    // 名前を指定して値を取得
+    //=========================================================================
+    pub fn get(&self, name: &str) -> Option<&str>
+    {
+        self.cookies
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    //=========================================================================
+    // すべての名前と値の組
+    //=========================================================================
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)>
+    {
+        self.cookies.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for Cookies
+    where
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        // HTTP/2ではCookieヘッダが複数に分かれることがある
+        let cookies = req.headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair|
+            {
+                let (name, value) = pair.trim().split_once('=')?;
+                let value = value.trim().trim_matches('"');
+                Some((name.trim().to_string(), value.to_string()))
+            })
+            .collect();
+
+        Ok(Self { cookies })
+    }
+}
+
+
+//=============================================================================
+// PeerAddr
+//
+// 接続元のアドレス
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+#[async_trait]
+impl<B> FromRequest<B> for PeerAddr
+    where
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        // サーバがリクエストに追加していなければ使えない
+        match req.extensions().get::<ConnectInfo<SocketAddr>>()
+        {
+            Some(ConnectInfo(peer)) => Ok(Self(*peer)),
+            None => Err(Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "peer address is not available",
+            )),
+        }
+    }
+}
+
+
+//=============================================================================
+// State
+//
+// App::state()またはApp::config()で登録した値
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct State<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for State<T>
+    where
+        T: Clone + Send + Sync + 'static,
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        match req.extensions().get::<T>()
+        {
+            Some(value) => Ok(Self(value.clone())),
+            None => Err(Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("state `{}` is not registered", type_name::<T>()),
+            )),
+        }
+    }
+}
@@ -0,0 +1,1579 @@
+use crate::config::IbisServerTokioConfig;
+use crate::error::ErrorTarget;
+use crate::extract::BodyLimit;
+use crate::http2::Rewind;
+use crate::shutdown::ShutdownSignal;
+use crate::upgrade;
+
+use std::fmt::Display;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::body::{ Body, BoxBody, Bytes, HttpBody };
+use axum::extract::ConnectInfo;
+use axum::http::header::{ self, HeaderName };
+use axum::http::{ HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version };
+
+use hyper::body::Sender;
+
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+
+use tower::{ Service, ServiceExt };
+
+use tracing::{ debug, error, info };
+
+
+// 1リクエストあたりのヘッダの最大数
+const MAX_HEADERS: usize = 100;
+
+// リクエストライン + ヘッダの最大サイズ
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// ソケットから一度に読み込むサイズ
+const READ_BUF_SIZE: usize = 8 * 1024;
+
+// チャンクサイズ及びトレーラの1行の最大サイズ
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+// チャンクサイズの最大桁数（u64に収まる16進数の桁数）
+const MAX_CHUNK_SIZE_DIGITS: usize = 16;
+
+
+//=============================================================================
+// ParseError
+//=============================================================================
+#[derive(Debug)]
+enum ParseError
+{
+    Closed,
+    Io(io::Error),
+    Invalid(&'static str),
+    HeaderTooLarge,
+    BodyTooLarge,
+    Unsupported(&'static str),
+    Timeout(&'static str),
+}
+
+impl ParseError
+{
+    //=========================================================================
+    // クライアントに返すステータスコードを取得
+    //=========================================================================
+    fn status(&self) -> Option<StatusCode>
+    {
+        match self
+        {
+            Self::Closed | Self::Io(_) => None,
+            Self::Invalid(_) => Some(StatusCode::BAD_REQUEST),
+            Self::HeaderTooLarge => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            Self::BodyTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            Self::Unsupported(_) => Some(StatusCode::NOT_IMPLEMENTED),
+            Self::Timeout("idle") => None,
+            Self::Timeout(_) => Some(StatusCode::REQUEST_TIMEOUT),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError
+{
+    fn from(e: io::Error) -> Self
+    {
+        Self::Io(e)
+    }
+}
+
+impl std::fmt::Display for ParseError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Self::Closed => f.write_str("connection closed"),
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Invalid(s) => write!(f, "invalid request: {}", s),
+            Self::HeaderTooLarge => f.write_str("request header too large"),
+            Self::BodyTooLarge => f.write_str("request body too large"),
+            Self::Unsupported(s) => write!(f, "unsupported request: {}", s),
+            Self::Timeout(s) => write!(f, "{} timed out", s),
+        }
+    }
+}
+
+
+//=============================================================================
+// Http1Config
+//
+// コネクションの再利用、タイムアウト及びボディの最大サイズの設定（Noneは無制限）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub(crate) struct Http1Config
+{
+    max_requests: Option<usize>,
+    header_read_timeout: Option<Duration>,
+    body_read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_body_size: Option<u64>,
+}
+
+impl Http1Config
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub(crate) fn new(config: &IbisServerTokioConfig) -> Self
+    {
+        let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+
+        Self
+        {
+            max_requests: (config.max_requests > 0).then_some(config.max_requests),
+            header_read_timeout: seconds(config.header_read_timeout),
+            body_read_timeout: seconds(config.body_read_timeout),
+            idle_timeout: seconds(config.idle_timeout),
+            max_body_size: (config.max_body_size > 0).then_some(config.max_body_size),
+        }
+    }
+
+    //=========================================================================
+    // ヘッダ読み込みのタイムアウトを取得
+    //=========================================================================
+    pub(crate) fn header_read_timeout(&self) -> Option<Duration>
+    {
+        self.header_read_timeout
+    }
+}
+
+
+//=============================================================================
+// 1つのコネクションを処理する
+//
+// keep-aliveのコネクションでは、パイプライン化されたリクエストも含めて
+// 受信した順に1件ずつ処理してレスポンスを書き出す。リクエストのボディは
+// ハンドラの読み込みに合わせてソケットから順に受け渡し、レスポンスの
+// ボディもチャンクごとに書き出すため、全体をメモリに溜めることはない。
+// 処理中はShutdownSignalを保持し、シャットダウンが通知されたら処理中の
+// リクエストに応答してからコネクションを閉じる。
+//=============================================================================
+pub(crate) async fn serve_connection<I, S, B>(
+    io: I,
+    peer: SocketAddr,
+    config: Http1Config,
+    service: S,
+    mut shutdown: ShutdownSignal,
+)
+    where
+        I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+        S: Service<Request<Body>, Response = Response<B>> + Clone,
+        S::Error: Display,
+        B: HttpBody<Data = Bytes>,
+        B::Error: Display,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let mut buf = Vec::with_capacity(READ_BUF_SIZE);
+    let mut served = 0;
+
+    loop
+    {
+        // リクエストの読み込み（リクエストを待っている間にシャットダウンが
+        // 通知されたら閉じる）
+        let request = tokio::select!
+        {
+            request = read_request(&mut reader, &mut buf, &config, served > 0) => request,
+            _ = shutdown.recv() =>
+            {
+                debug!("connection from {} closed by shutdown", peer);
+                return;
+            },
+        };
+        let (mut request, length) = match request
+        {
+            Ok(request) => request,
+            Err(ParseError::Closed) => return,
+            Err(e) =>
+            {
+                log_parse_error(peer, &e);
+                if let Some(status) = e.status()
+                {
+                    let response = error_response(&ErrorTarget::new(None, None), status, &e);
+                    let _ = write_response(&mut writer, &Method::GET, Version::HTTP_11, response, false).await;
+                }
+                return;
+            },
+        };
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request.extensions_mut().insert(shutdown.clone());
+        if let Some(max) = config.max_body_size
+        {
+            // ボディの上限はここで確認するため、extract::body_limit_layer()では確認しない
+            request.extensions_mut().insert(BodyLimit(max));
+        }
+        served += 1;
+
+        // ハンドラに渡した後のエラーのレスポンスの形式を選ぶために残しておく
+        let uri = request.uri().clone();
+        let accept = request.headers().get(header::ACCEPT).cloned();
+
+        // Content-Lengthが上限を超えていればボディを読まずに413を返して閉じる
+        if let (BodyLength::Length(length), Some(max)) = (length, config.max_body_size)
+        {
+            if length > max
+            {
+                let e = ParseError::BodyTooLarge;
+                log_parse_error(peer, &e);
+                let target = ErrorTarget::new(accept.as_ref(), Some(uri.path()));
+                let response = error_response(&target, StatusCode::PAYLOAD_TOO_LARGE, &e);
+                let _ = write_response(&mut writer, request.method(), request.version(), response, false).await;
+                return;
+            }
+        }
+
+        // プロトコルの切り替え（WebSocketなど）の要求
+        let upgrade = match has_connection_token(request.headers(), "upgrade")
+        {
+            true =>
+            {
+                let (pending, on_upgrade) = upgrade::pending();
+                request.extensions_mut().insert(on_upgrade);
+                Some(pending)
+            },
+            false => None,
+        };
+
+        let method = request.method().clone();
+        let version = request.version();
+        let expect_continue = version == Version::HTTP_11
+            && request.headers()
+                .get(header::EXPECT)
+                .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+        let mut keep_alive = wants_keep_alive(version, request.headers())
+            && config.max_requests.is_none_or(|max| served < max);
+
+        // ボディはハンドラが読み込むのと並行してソケットから受け渡す
+        let sender = match length
+        {
+            BodyLength::Empty => None,
+            _ =>
+            {
+                let (sender, body) = Body::channel();
+                *request.body_mut() = body;
+                Some(sender)
+            },
+        };
+        let feed = async
+        {
+            match sender
+            {
+                Some(sender) =>
+                {
+                    if expect_continue
+                    {
+                        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                        writer.flush().await?;
+                    }
+                    read_body(&mut reader, &mut buf, length, sender, &config).await
+                },
+                None => Ok(true),
+            }
+        };
+
+        // サービスの呼び出し
+        let (response, fed) = tokio::join!(service.clone().oneshot(request), feed);
+
+        // ボディを最後まで読めなかった場合は、次のリクエストの境界が
+        // 分からないためコネクションを閉じる
+        let body_complete = match &fed
+        {
+            Ok(complete) => *complete,
+            Err(e) =>
+            {
+                log_parse_error(peer, e);
+                false
+            },
+        };
+        keep_alive = keep_alive && body_complete;
+
+        // ボディが上限を超えた、タイムアウトした、形式が不正だった場合は、
+        // ハンドラのレスポンスの代わりにエラーを返して閉じる
+        let target = || ErrorTarget::new(accept.as_ref(), Some(uri.path()));
+        if let Err(e) = &fed
+        {
+            if let Some(status) = e.status()
+            {
+                let response = error_response(&target(), status, e);
+                if let Err(e) = write_response(&mut writer, &method, version, response, false).await
+                {
+                    error!("failed to write to socket: {}", e);
+                }
+                return;
+            }
+        }
+
+        let response = match response
+        {
+            Ok(response) => response,
+            Err(e) =>
+            {
+                let e = format!("service error: {}", e);
+                let response = error_response(&target(), StatusCode::INTERNAL_SERVER_ERROR, e);
+                if let Err(e) = write_response(&mut writer, &method, version, response, false).await
+                {
+                    error!("failed to write to socket: {}", e);
+                }
+                return;
+            },
+        };
+
+        // 101を返した場合はレスポンスの後のコネクションをハンドラへ渡す
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS
+        {
+            let pending = match (upgrade, body_complete)
+            {
+                (Some(pending), true) => pending,
+                _ =>
+                {
+                    error!("switching protocols without an upgrade request from {}", peer);
+                    return;
+                },
+            };
+            if let Err(e) = write_response(&mut writer, &method, version, response, true).await
+            {
+                error!("failed to write to socket: {}", e);
+                return;
+            }
+
+            let io = reader.unsplit(writer);
+            pending.fulfill(Box::new(Rewind::new(io, buf)));
+            return;
+        }
+
+        // ハンドラがConnection: closeを指定した場合やシャットダウン中は閉じる
+        keep_alive = keep_alive
+            && !has_connection_token(response.headers(), "close")
+            && !shutdown.is_shutdown();
+
+        // クライアントへのレスポンス
+        match write_response(&mut writer, &method, version, response, keep_alive).await
+        {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(e) =>
+            {
+                error!("failed to write to socket: {}", e);
+                return;
+            },
+        }
+    }
+}
+
+
+//=============================================================================
+// リクエストの読み込みエラーをログに出力
+//=============================================================================
+fn log_parse_error(peer: SocketAddr, e: &ParseError)
+{
+    match e
+    {
+        ParseError::Timeout(_) => info!("connection from {} closed: {}", peer, e),
+        _ => debug!("failed to read request from {}: {}", peer, e),
+    }
+}
+
+
+//=============================================================================
+// コネクションを維持するかどうか
+//
+// HTTP/1.1はConnection: closeがなければ維持し、HTTP/1.0は
+// Connection: keep-aliveがあれば維持する。
+//=============================================================================
+fn wants_keep_alive(version: Version, headers: &HeaderMap) -> bool
+{
+    match version
+    {
+        Version::HTTP_11 => !has_connection_token(headers, "close"),
+        _ => has_connection_token(headers, "keep-alive"),
+    }
+}
+
+
+//=============================================================================
+// Connectionヘッダに指定のトークンが含まれるか
+//=============================================================================
+fn has_connection_token(headers: &HeaderMap, token: &str) -> bool
+{
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+
+//=============================================================================
+// リクエストライン及びヘッダを読み込み、ボディの長さを判定
+//
+// 前のリクエストに続く場合は、次のリクエストの先頭を受信するまで
+// idle_timeoutだけ待つ。
+//=============================================================================
+async fn read_request<I>(io: &mut I, buf: &mut Vec<u8>, config: &Http1Config, idle: bool)
+    -> Result<(Request<Body>, BodyLength), ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    if idle && buf.is_empty()
+    {
+        let n = with_timeout(config.idle_timeout, "idle", read_more(io, buf)).await??;
+        if n == 0
+        {
+            return Err(ParseError::Closed);
+        }
+    }
+
+    let request = with_timeout(
+        config.header_read_timeout,
+        "header read",
+        read_head(io, buf)
+    ).await??;
+
+    let length = body_length(request.headers())?;
+    Ok((request, length))
+}
+
+
+//=============================================================================
+// BodyLength
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+enum BodyLength
+{
+    Empty,
+    Length(u64),
+    Chunked,
+}
+
+
+//=============================================================================
+// ヘッダからリクエストボディの長さを判定
+//=============================================================================
+fn body_length(headers: &HeaderMap) -> Result<BodyLength, ParseError>
+{
+    if let Some(value) = headers.get(header::TRANSFER_ENCODING)
+    {
+        // 両方が指定されたリクエストは境界が曖昧になるため受け付けない
+        if headers.contains_key(header::CONTENT_LENGTH)
+        {
+            return Err(ParseError::Invalid("both transfer-encoding and content-length"));
+        }
+
+        return match value.as_bytes().trim_ascii().eq_ignore_ascii_case(b"chunked")
+        {
+            true => Ok(BodyLength::Chunked),
+            false => Err(ParseError::Unsupported("transfer-encoding")),
+        };
+    }
+
+    match headers.get(header::CONTENT_LENGTH)
+    {
+        Some(value) =>
+        {
+            let length = value
+                .to_str()
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .ok_or(ParseError::Invalid("content-length"))?;
+
+            match length
+            {
+                0 => Ok(BodyLength::Empty),
+                length => Ok(BodyLength::Length(length)),
+            }
+        },
+        None => Ok(BodyLength::Empty),
+    }
+}
+
+
+//=============================================================================
+// リクエストボディを読み込んでハンドラへ渡す
+//
+// ハンドラがボディを読まずに破棄した場合は途中で止めてfalseを返す。
+// 読み込みに失敗した場合やチャンク形式のボディが上限を超えた場合は
+// ハンドラ側のボディにもエラーを通知する。
+//=============================================================================
+async fn read_body<I>(
+    io: &mut I,
+    buf: &mut Vec<u8>,
+    length: BodyLength,
+    mut sender: Sender,
+    config: &Http1Config,
+) -> Result<bool, ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    let timeout = config.body_read_timeout;
+    let result = match length
+    {
+        BodyLength::Empty => Ok(true),
+        BodyLength::Length(length) => forward(io, buf, length, &mut sender, timeout).await,
+        BodyLength::Chunked =>
+        {
+            read_chunked(io, buf, &mut sender, timeout, config.max_body_size).await
+        },
+    };
+
+    if result.is_err()
+    {
+        sender.abort();
+    }
+    result
+}
+
+
+//=============================================================================
+// チャンク形式のボディを読み込み
+//=============================================================================
+async fn read_chunked<I>(
+    io: &mut I,
+    buf: &mut Vec<u8>,
+    sender: &mut Sender,
+    timeout: Option<Duration>,
+    max_body_size: Option<u64>,
+) -> Result<bool, ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    let mut total: u64 = 0;
+    loop
+    {
+        // チャンクサイズ（拡張は無視する）
+        let line = read_line(io, buf, timeout).await?;
+        let size = chunk_size(&line)?;
+
+        if size == 0
+        {
+            break;
+        }
+
+        // 上限を超えるチャンクは受け取らない
+        total = total.saturating_add(size);
+        if max_body_size.is_some_and(|max| total > max)
+        {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        if !forward(io, buf, size, sender, timeout).await?
+        {
+            return Ok(false);
+        }
+
+        if !read_line(io, buf, timeout).await?.is_empty()
+        {
+            return Err(ParseError::Invalid("chunk data"));
+        }
+    }
+
+    // トレーラ
+    let mut trailers = HeaderMap::new();
+    loop
+    {
+        let line = read_line(io, buf, timeout).await?;
+        if line.is_empty()
+        {
+            break;
+        }
+        if trailers.len() >= MAX_HEADERS
+        {
+            return Err(ParseError::HeaderTooLarge);
+        }
+
+        let (name, value) = line.split_at(
+            line.iter().position(|b| *b == b':').ok_or(ParseError::Invalid("trailer"))?
+        );
+        let name = HeaderName::from_bytes(name.trim_ascii())
+            .map_err(|_| ParseError::Invalid("trailer"))?;
+        let value = HeaderValue::from_bytes(value[1..].trim_ascii())
+            .map_err(|_| ParseError::Invalid("trailer"))?;
+        trailers.append(name, value);
+    }
+
+    if !trailers.is_empty()
+    {
+        let _ = sender.send_trailers(trailers).await;
+    }
+    Ok(true)
+}
+
+
+//=============================================================================
+// チャンクサイズの行を解析
+//
+// プロキシとの解釈の違いを避けるため、1*HEXDIGの後に拡張（;）が続くか
+// 行末であるものだけを受け付ける。空白や符号、桁数の多すぎるものは不正。
+//=============================================================================
+fn chunk_size(line: &[u8]) -> Result<u64, ParseError>
+{
+    let digits = line.iter()
+        .position(|b| *b == b';')
+        .map_or(line, |end| &line[..end]);
+    if digits.is_empty()
+        || digits.len() > MAX_CHUNK_SIZE_DIGITS
+        || !digits.iter().all(u8::is_ascii_hexdigit)
+    {
+        return Err(ParseError::Invalid("chunk size"));
+    }
+
+    digits.iter().try_fold(0u64, |size, digit|
+    {
+        let value = (*digit as char).to_digit(16).ok_or(ParseError::Invalid("chunk size"))?;
+        Ok(size << 4 | u64::from(value))
+    })
+}
+
+
+//=============================================================================
+// 指定の長さのデータをハンドラへ渡す
+//=============================================================================
+async fn forward<I>(
+    io: &mut I,
+    buf: &mut Vec<u8>,
+    length: u64,
+    sender: &mut Sender,
+    timeout: Option<Duration>,
+) -> Result<bool, ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    let mut remaining = length;
+    while remaining > 0
+    {
+        if buf.is_empty()
+        {
+            fill(io, buf, timeout).await?;
+        }
+
+        let n = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let data = Bytes::from(buf.drain(..n).collect::<Vec<u8>>());
+        remaining -= n as u64;
+
+        if sender.send_data(data).await.is_err() && remaining > 0
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+
+//=============================================================================
+// CRLFまでの1行を読み込み（CRLFは含まない）
+//=============================================================================
+async fn read_line<I>(io: &mut I, buf: &mut Vec<u8>, timeout: Option<Duration>)
+    -> Result<Vec<u8>, ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    loop
+    {
+        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n")
+        {
+            let line = buf[..pos].to_vec();
+            buf.drain(..pos + 2);
+            return Ok(line);
+        }
+        if buf.len() >= MAX_LINE_SIZE
+        {
+            return Err(ParseError::Invalid("chunk line too long"));
+        }
+
+        fill(io, buf, timeout).await?;
+    }
+}
+
+
+//=============================================================================
+// ボディの続きをソケットから読み込み
+//=============================================================================
+async fn fill<I>(io: &mut I, buf: &mut Vec<u8>, timeout: Option<Duration>)
+    -> Result<(), ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    match with_timeout(timeout, "body read", read_more(io, buf)).await??
+    {
+        0 => Err(ParseError::Invalid("unexpected eof in body")),
+        _ => Ok(()),
+    }
+}
+
+
+//=============================================================================
+// タイムアウト付きで実行
+//=============================================================================
+async fn with_timeout<F, T>(timeout: Option<Duration>, phase: &'static str, future: F)
+    -> Result<T, ParseError>
+    where
+        F: Future<Output = T>,
+{
+    match timeout
+    {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| ParseError::Timeout(phase)),
+        None => Ok(future.await),
+    }
+}
+
+
+//=============================================================================
+// リクエストライン及びヘッダを読み込み
+//=============================================================================
+async fn read_head<I>(io: &mut I, buf: &mut Vec<u8>)
+    -> Result<Request<Body>, ParseError>
+    where
+        I: AsyncRead + Unpin,
+{
+    loop
+    {
+        if !buf.is_empty()
+        {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut parsed = httparse::Request::new(&mut headers);
+
+            match parsed.parse(buf)
+            {
+                Ok(httparse::Status::Complete(len)) =>
+                {
+                    let request = build_request(&parsed)?;
+                    buf.drain(..len);
+                    return Ok(request);
+                },
+                Ok(httparse::Status::Partial) => {},
+                Err(httparse::Error::TooManyHeaders) =>
+                {
+                    return Err(ParseError::HeaderTooLarge);
+                },
+                Err(_) => return Err(ParseError::Invalid("malformed request head")),
+            }
+
+            if buf.len() >= MAX_HEAD_SIZE
+            {
+                return Err(ParseError::HeaderTooLarge);
+            }
+        }
+
+        if read_more(io, buf).await? == 0
+        {
+            return match buf.is_empty()
+            {
+                true => Err(ParseError::Closed),
+                false => Err(ParseError::Invalid("unexpected eof in head")),
+            };
+        }
+    }
+}
+
+
+//=============================================================================
+// パース結果からRequestを生成
+//=============================================================================
+fn build_request(parsed: &httparse::Request) -> Result<Request<Body>, ParseError>
+{
+    let version = match parsed.version
+    {
+        Some(0) => Version::HTTP_10,
+        Some(1) => Version::HTTP_11,
+        _ => return Err(ParseError::Unsupported("http version")),
+    };
+
+    let mut builder = Request::builder()
+        .method(parsed.method.unwrap_or_default())
+        .uri(parsed.path.unwrap_or_default())
+        .version(version);
+
+    for h in parsed.headers.iter()
+    {
+        builder = builder.header(h.name, h.value);
+    }
+
+    builder
+        .body(Body::empty())
+        .map_err(|_| ParseError::Invalid("request line or header"))
+}
+
+
+//=============================================================================
+// ソケットから追加で読み込み
+//=============================================================================
+async fn read_more<I>(io: &mut I, buf: &mut Vec<u8>) -> io::Result<usize>
+    where
+        I: AsyncRead + Unpin,
+{
+    let mut chunk = [0; READ_BUF_SIZE];
+    let n = io.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+
+//=============================================================================
+// Framing
+//
+// レスポンスボディの区切り方
+//=============================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing
+{
+    None,
+    Length(u64),
+    Chunked,
+    Close,
+}
+
+
+//=============================================================================
+// レスポンスを書き出し
+//
+// ボディの長さが分かっていればContent-Length、分からなければ
+// チャンク形式（HTTP/1.0ではコネクションの切断）で区切り、チャンクごとに
+// 書き出す。コネクションを維持できる場合はtrueを返す。
+//=============================================================================
+async fn write_response<I, B>(
+    io: &mut I,
+    method: &Method,
+    version: Version,
+    response: Response<B>,
+    keep_alive: bool,
+) -> io::Result<bool>
+    where
+        I: AsyncWrite + Unpin,
+        B: HttpBody<Data = Bytes>,
+        B::Error: Display,
+{
+    let (parts, body) = response.into_parts();
+    let mut headers = parts.headers;
+
+    let status = parts.status;
+    let has_body = !(status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED);
+
+    let framing = match has_body
+    {
+        false => Framing::None,
+        true => match body.size_hint().exact()
+        {
+            Some(length) => Framing::Length(length),
+            None => match content_length(&headers)
+            {
+                Some(length) => Framing::Length(length),
+                None if version == Version::HTTP_11 => Framing::Chunked,
+                None => Framing::Close,
+            },
+        },
+    };
+    let keep_alive = keep_alive && framing != Framing::Close;
+
+    let mut head = Vec::with_capacity(256);
+    head.extend_from_slice(format!(
+        "HTTP/1.1 {} {}\r\n",
+        status.as_str(),
+        status.canonical_reason().unwrap_or("")
+    ).as_bytes());
+
+    set_default_headers(&mut headers, framing, keep_alive);
+    if status == StatusCode::SWITCHING_PROTOCOLS
+    {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+    write_headers(&mut head, &headers);
+    head.extend_from_slice(b"\r\n");
+
+    io.write_all(&head).await?;
+    if framing == Framing::None || method == Method::HEAD
+    {
+        io.flush().await?;
+        return Ok(keep_alive);
+    }
+
+    // ボディをチャンクごとに書き出し
+    tokio::pin!(body);
+    let mut written: u64 = 0;
+    while let Some(chunk) = body.data().await
+    {
+        let chunk = chunk.map_err(|e| io::Error::other(e.to_string()))?;
+        if chunk.is_empty()
+        {
+            continue;
+        }
+        written += chunk.len() as u64;
+
+        match framing
+        {
+            Framing::Chunked =>
+            {
+                let mut data = Vec::with_capacity(chunk.len() + 16);
+                data.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                data.extend_from_slice(&chunk);
+                data.extend_from_slice(b"\r\n");
+                io.write_all(&data).await?;
+            },
+            Framing::Length(length) if written > length =>
+            {
+                return Err(io::Error::other("response body longer than content-length"));
+            },
+            _ => io.write_all(&chunk).await?,
+        }
+        io.flush().await?;
+    }
+
+    match framing
+    {
+        Framing::Chunked =>
+        {
+            let mut tail = b"0\r\n".to_vec();
+            let trailers = body.trailers()
+                .await
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            if let Some(trailers) = trailers
+            {
+                write_headers(&mut tail, &trailers);
+            }
+            tail.extend_from_slice(b"\r\n");
+            io.write_all(&tail).await?;
+        },
+        Framing::Length(length) if written != length =>
+        {
+            return Err(io::Error::other("response body shorter than content-length"));
+        },
+        _ => {},
+    }
+
+    io.flush().await?;
+    Ok(keep_alive)
+}
+
+
+//=============================================================================
+// ヘッダを書き出し用のバッファに追加
+//=============================================================================
+fn write_headers(buf: &mut Vec<u8>, headers: &HeaderMap)
+{
+    for (name, value) in headers.iter()
+    {
+        buf.extend_from_slice(name.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+
+//=============================================================================
+// ハンドラが指定したContent-Length
+//=============================================================================
+fn content_length(headers: &HeaderMap) -> Option<u64>
+{
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+
+//=============================================================================
+// フレームワークが管理するヘッダを設定
+//=============================================================================
+fn set_default_headers(headers: &mut HeaderMap, framing: Framing, keep_alive: bool)
+{
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.remove(header::CONTENT_LENGTH);
+    match framing
+    {
+        Framing::Length(length) =>
+        {
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+        },
+        Framing::Chunked =>
+        {
+            headers.insert(header::TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        },
+        Framing::None | Framing::Close => {},
+    }
+
+    if !headers.contains_key(header::DATE)
+    {
+        let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        if let Ok(date) = HeaderValue::from_str(&date)
+        {
+            headers.insert(header::DATE, date);
+        }
+    }
+
+    let connection = match keep_alive
+    {
+        true => "keep-alive",
+        false => "close",
+    };
+    headers.insert(header::CONNECTION, HeaderValue::from_static(connection));
+}
+
+
+//=============================================================================
+// ハンドラを通らないエラーのレスポンスを作成
+//
+// ハンドラのエラーと同じくcrate::Errorから作成し、クライアントに応じた
+// 形式で返す（5xxは原因をログにだけ出力する）。
+//=============================================================================
+fn error_response<E>(target: &ErrorTarget, status: StatusCode, error: E) -> Response<BoxBody>
+    where
+        E: ToString,
+{
+    target.response(crate::Error::from_status(status, error.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use crate::shutdown::Shutdown;
+
+    use std::convert::Infallible;
+
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    use tower::service_fn;
+
+
+    //=========================================================================
+    // 制限のない設定
+    //=========================================================================
+    fn config() -> Http1Config
+    {
+        Http1Config
+        {
+            max_requests: None,
+            header_read_timeout: None,
+            body_read_timeout: None,
+            idle_timeout: None,
+            max_body_size: None,
+        }
+    }
+
+    //=========================================================================
+    // 受け取ったリクエストの内容を本文にして返すサービス
+    //
+    // /streamは長さの分からないボディを返す。
+    //=========================================================================
+    async fn echo(request: Request<Body>) -> Result<Response<Body>, Infallible>
+    {
+        if request.uri().path() == "/stream"
+        {
+            let chunks = futures_util::stream::iter([Ok::<_, Infallible>("hello "), Ok("world")]);
+            return Ok(Response::new(Body::wrap_stream(chunks)));
+        }
+
+        let summary = format!(
+            "{} {} {:?} x-name={}",
+            request.method(),
+            request.uri(),
+            request.version(),
+            request.headers()
+                .get("x-name")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-"),
+        );
+
+        let mut body = request.into_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await
+        {
+            match chunk
+            {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(_) => return Ok(Response::new(Body::from("body error"))),
+            }
+        }
+        let trailers = match body.trailers().await
+        {
+            Ok(Some(trailers)) => trailers.iter()
+                .map(|(name, value)| format!("{}={}", name, value.to_str().unwrap_or("")))
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => "-".to_string(),
+        };
+
+        Ok(Response::new(Body::from(format!(
+            "{} body={} trailers={}",
+            summary,
+            String::from_utf8_lossy(&data),
+            trailers,
+        ))))
+    }
+
+    //=========================================================================
+    // コネクションの処理を開始（Shutdownはdropするとシャットダウンになる）
+    //=========================================================================
+    fn start(config: Http1Config) -> (DuplexStream, JoinHandle<()>, Shutdown)
+    {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let shutdown = Shutdown::new();
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let handle = tokio::spawn(serve_connection(
+            server,
+            peer,
+            config,
+            service_fn(echo),
+            shutdown.signal(),
+        ));
+        (client, handle, shutdown)
+    }
+
+    //=========================================================================
+    // サーバがコネクションを閉じるまで読み込む
+    //=========================================================================
+    async fn read_until_closed(client: &mut DuplexStream) -> String
+    {
+        let mut data = Vec::new();
+        tokio::time::timeout(Duration::from_secs(60), client.read_to_end(&mut data))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        String::from_utf8_lossy(&data).into_owned()
+    }
+
+    //=========================================================================
+    // リクエストを送り、送信側を閉じてからすべてのレスポンスを読み込む
+    //=========================================================================
+    async fn exchange(config: Http1Config, request: &str) -> Vec<TestResponse>
+    {
+        let (mut client, handle, _shutdown) = start(config);
+        client.write_all(request.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let raw = read_until_closed(&mut client).await;
+        handle.await.unwrap();
+        parse_responses(&raw)
+    }
+
+    //=========================================================================
+    // リクエストを送り、送信側を閉じずにサーバが閉じるまで読み込む
+    //=========================================================================
+    async fn exchange_until_closed(config: Http1Config, request: &str) -> Vec<TestResponse>
+    {
+        let (mut client, handle, _shutdown) = start(config);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let raw = read_until_closed(&mut client).await;
+        handle.await.unwrap();
+        parse_responses(&raw)
+    }
+
+
+    //=========================================================================
+    // TestResponse
+    //=========================================================================
+    #[derive(Debug)]
+    struct TestResponse
+    {
+        status: u16,
+        headers: HeaderMap,
+        body: String,
+    }
+
+    impl TestResponse
+    {
+        fn header(&self, name: &str) -> Option<&str>
+        {
+            self.headers.get(name).and_then(|value| value.to_str().ok())
+        }
+    }
+
+    //=========================================================================
+    // 受信したバイト列をレスポンスに分割
+    //
+    // Content-Length、チャンク形式、コネクションの切断のいずれかで区切る。
+    //=========================================================================
+    fn parse_responses(raw: &str) -> Vec<TestResponse>
+    {
+        let mut responses = Vec::new();
+        let mut rest = raw.as_bytes();
+        while !rest.is_empty()
+        {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut parsed = httparse::Response::new(&mut headers);
+            let len = match parsed.parse(rest).expect("malformed response")
+            {
+                httparse::Status::Complete(len) => len,
+                httparse::Status::Partial => panic!("partial response: {:?}", raw),
+            };
+
+            let mut map = HeaderMap::new();
+            for h in parsed.headers.iter()
+            {
+                map.append(
+                    HeaderName::from_bytes(h.name.as_bytes()).unwrap(),
+                    HeaderValue::from_bytes(h.value).unwrap(),
+                );
+            }
+            let status = parsed.code.unwrap();
+            rest = &rest[len..];
+
+            let body = if status == 100
+            {
+                Vec::new()
+            }
+            else if let Some(length) = content_length(&map)
+            {
+                let (body, next) = rest.split_at(length as usize);
+                rest = next;
+                body.to_vec()
+            }
+            else if map.contains_key(header::TRANSFER_ENCODING)
+            {
+                let mut body = Vec::new();
+                loop
+                {
+                    let end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+                    let size = usize::from_str_radix(std::str::from_utf8(&rest[..end]).unwrap(), 16)
+                        .unwrap();
+                    rest = &rest[end + 2..];
+                    if size == 0
+                    {
+                        let end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+                        rest = &rest[end + 2..];
+                        break;
+                    }
+                    body.extend_from_slice(&rest[..size]);
+                    rest = &rest[size + 2..];
+                }
+                body
+            }
+            else
+            {
+                std::mem::take(&mut rest).to_vec()
+            };
+
+            responses.push(TestResponse
+            {
+                status,
+                headers: map,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            });
+        }
+        responses
+    }
+
+
+    //=========================================================================
+    // リクエストライン、ヘッダ、ボディを解析してサービスに渡す
+    //=========================================================================
+    #[tokio::test]
+    async fn parses_request_line_and_headers()
+    {
+        let responses = exchange(
+            config(),
+            "POST /users?page=2 HTTP/1.1\r\nHost: example.com\r\nX-Name: ibis\r\n\
+             Content-Length: 5\r\n\r\nhello",
+        ).await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(
+            responses[0].body,
+            "POST /users?page=2 HTTP/1.1 x-name=ibis body=hello trailers=-"
+        );
+        assert_eq!(responses[0].header("content-length"), Some("61"));
+    }
+
+    //=========================================================================
+    // 不正なリクエストには400を返して閉じる
+    //=========================================================================
+    #[tokio::test]
+    async fn malformed_requests_return_400()
+    {
+        let requests = [
+            "GARBAGE\r\n\r\n",
+            "GET / HTTP/1.1\r\nBad Header\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Name: a\rb\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n",
+        ];
+        for request in requests
+        {
+            let responses = exchange_until_closed(config(), request).await;
+            assert_eq!(responses.len(), 1, "{:?}", request);
+            assert_eq!(responses[0].status, 400, "{:?}", request);
+            assert_eq!(responses[0].header("connection"), Some("close"), "{:?}", request);
+        }
+    }
+
+    //=========================================================================
+    // keep-aliveのコネクションではパイプライン化されたリクエストに順に応答する
+    //=========================================================================
+    #[tokio::test]
+    async fn keep_alive_pipelining()
+    {
+        let responses = exchange(
+            config(),
+            "GET /first HTTP/1.1\r\nHost: x\r\n\r\n\
+             POST /second HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc",
+        ).await;
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].body, "GET /first HTTP/1.1 x-name=- body= trailers=-");
+        assert_eq!(responses[0].header("connection"), Some("keep-alive"));
+        assert_eq!(responses[1].body, "POST /second HTTP/1.1 x-name=- body=abc trailers=-");
+        assert_eq!(responses[1].header("connection"), Some("keep-alive"));
+    }
+
+    //=========================================================================
+    // Connection: closeのリクエストに応答したら閉じる
+    //=========================================================================
+    #[tokio::test]
+    async fn connection_close()
+    {
+        let responses = exchange_until_closed(
+            config(),
+            "GET /first HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n\
+             GET /second HTTP/1.1\r\nHost: x\r\n\r\n",
+        ).await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].body, "GET /first HTTP/1.1 x-name=- body= trailers=-");
+        assert_eq!(responses[0].header("connection"), Some("close"));
+    }
+
+    //=========================================================================
+    // HTTP/1.0はConnection: keep-aliveがなければ応答後に閉じる
+    //=========================================================================
+    #[tokio::test]
+    async fn http10_without_keep_alive()
+    {
+        let responses = exchange_until_closed(
+            config(),
+            "GET /first HTTP/1.0\r\n\r\nGET /second HTTP/1.0\r\n\r\n",
+        ).await;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].body, "GET /first HTTP/1.0 x-name=- body= trailers=-");
+        assert_eq!(responses[0].header("connection"), Some("close"));
+
+        let responses = exchange(
+            config(),
+            "GET /first HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+             GET /second HTTP/1.0\r\n\r\n",
+        ).await;
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].header("connection"), Some("keep-alive"));
+        assert_eq!(responses[1].header("connection"), Some("close"));
+    }
+
+    //=========================================================================
+    // ヘッダの受信が時間内に終わらなければ408を返して閉じる
+    //=========================================================================
+    #[tokio::test(start_paused = true)]
+    async fn header_read_timeout_returns_408()
+    {
+        let config = Http1Config
+        {
+            header_read_timeout: Some(Duration::from_secs(5)),
+            ..config()
+        };
+        let started = tokio::time::Instant::now();
+        let responses = exchange_until_closed(config, "GET / HTTP/1.1\r\nHost: x\r\n").await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 408);
+        assert_eq!(responses[0].header("connection"), Some("close"));
+        assert_eq!(started.elapsed().as_secs(), 5);
+    }
+
+    //=========================================================================
+    // ボディの受信が時間内に終わらなければ408を返して閉じる
+    //=========================================================================
+    #[tokio::test(start_paused = true)]
+    async fn body_read_timeout_returns_408()
+    {
+        let config = Http1Config
+        {
+            body_read_timeout: Some(Duration::from_secs(5)),
+            ..config()
+        };
+        let responses = exchange_until_closed(
+            config,
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nabc",
+        ).await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 408);
+    }
+
+    //=========================================================================
+    // 次のリクエストが時間内に来なければ何も返さずに閉じる
+    //=========================================================================
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_closes_connection()
+    {
+        let config = Http1Config
+        {
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..config()
+        };
+        let started = tokio::time::Instant::now();
+        let responses = exchange_until_closed(config, "GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(responses[0].header("connection"), Some("keep-alive"));
+        assert_eq!(started.elapsed().as_secs(), 30);
+    }
+
+    //=========================================================================
+    // max_requestsに達したらConnection: closeを返して閉じる
+    //=========================================================================
+    #[tokio::test(start_paused = true)]
+    async fn max_requests_closes_connection()
+    {
+        let config = Http1Config
+        {
+            max_requests: Some(2),
+            ..config()
+        };
+        let responses = exchange_until_closed(
+            config,
+            "GET /1 HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /2 HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /3 HTTP/1.1\r\nHost: x\r\n\r\n",
+        ).await;
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].header("connection"), Some("keep-alive"));
+        assert_eq!(responses[1].body, "GET /2 HTTP/1.1 x-name=- body= trailers=-");
+        assert_eq!(responses[1].header("connection"), Some("close"));
+    }
+
+    //=========================================================================
+    // チャンク形式のボディとトレーラをハンドラに渡す
+    //=========================================================================
+    #[tokio::test]
+    async fn decodes_chunked_body_with_trailers()
+    {
+        let responses = exchange(
+            config(),
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;name=value\r\nhello\r\nB\r\n, world!!!!\r\n0\r\nX-Checksum: abc\r\n\r\n\
+             GET /next HTTP/1.1\r\nHost: x\r\n\r\n",
+        ).await;
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(
+            responses[0].body,
+            "POST / HTTP/1.1 x-name=- body=hello, world!!!! trailers=x-checksum=abc"
+        );
+        assert_eq!(responses[1].body, "GET /next HTTP/1.1 x-name=- body= trailers=-");
+    }
+
+    //=========================================================================
+    // 不正なチャンクサイズには400を返して閉じる
+    //=========================================================================
+    #[tokio::test]
+    async fn malformed_chunk_size_returns_400()
+    {
+        let sizes = [" 5", "5 ", "+5", "0x5", "", "g", "10000000000000000"];
+        for size in sizes
+        {
+            let request = format!(
+                "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 {}\r\nhello\r\n0\r\n\r\n",
+                size,
+            );
+            let responses = exchange_until_closed(config(), &request).await;
+            assert_eq!(responses.len(), 1, "{:?}", size);
+            assert_eq!(responses[0].status, 400, "{:?}", size);
+            assert_eq!(responses[0].header("connection"), Some("close"), "{:?}", size);
+        }
+    }
+
+    //=========================================================================
+    // Expect: 100-continueには100を返してからボディを受け取る
+    //=========================================================================
+    #[tokio::test]
+    async fn expect_continue()
+    {
+        let (mut client, handle, _shutdown) = start(config());
+        client.write_all(
+            b"POST / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n"
+        ).await.unwrap();
+
+        let expected = b"HTTP/1.1 100 Continue\r\n\r\n";
+        let mut interim = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(60), client.read_exact(&mut interim))
+            .await
+            .expect("100 Continue was not sent")
+            .unwrap();
+        assert_eq!(interim, expected);
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let responses = parse_responses(&read_until_closed(&mut client).await);
+        handle.await.unwrap();
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 200);
+        assert_eq!(responses[0].body, "POST / HTTP/1.1 x-name=- body=hello trailers=-");
+    }
+
+    //=========================================================================
+    // Content-Lengthがmax_body_sizeを超えていれば413を返して閉じる
+    //=========================================================================
+    #[tokio::test]
+    async fn content_length_over_limit_returns_413()
+    {
+        let config = Http1Config
+        {
+            max_body_size: Some(4),
+            ..config()
+        };
+        let responses = exchange_until_closed(
+            config.clone(),
+            "POST / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n",
+        ).await;
+
+        // ボディを送らせる前に断るため100 Continueは返さない
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 413);
+        assert_eq!(responses[0].header("connection"), Some("close"));
+
+        // チャンク形式では受信した合計が上限を超えた時点で断る
+        let responses = exchange_until_closed(
+            config,
+            "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+             3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n",
+        ).await;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].status, 413);
+        assert_eq!(responses[0].header("connection"), Some("close"));
+    }
+
+    //=========================================================================
+    // 長さの分からないレスポンスはチャンク形式で送る
+    //=========================================================================
+    #[tokio::test]
+    async fn streams_response_in_chunks()
+    {
+        let (mut client, handle, _shutdown) = start(config());
+        client.write_all(b"GET /stream HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        let raw = read_until_closed(&mut client).await;
+        handle.await.unwrap();
+
+        let responses = parse_responses(&raw);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].header("transfer-encoding"), Some("chunked"));
+        assert_eq!(responses[0].header("content-length"), None);
+        assert_eq!(responses[0].body, "hello world");
+        assert!(raw.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"), "{:?}", raw);
+    }
+}
@@ -0,0 +1,134 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use std::time::Duration;
+
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf };
+
+
+// HTTP/2のコネクションプリフェイス
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+
+//=============================================================================
+// コネクションの先頭がHTTP/2のプリフェイスかどうかを判定
+//
+// TLSのALPNでh2が選ばれた場合もh2cのprior knowledgeの場合も、クライアントは
+// 最初にプリフェイスを送る。判定のために読み込んだデータはRewindで
+// 読み直せるように返す。
+//=============================================================================
+pub(crate) async fn detect<I>(io: &mut I, timeout: Option<Duration>)
+    -> io::Result<(bool, Vec<u8>)>
+    where
+        I: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(PREFACE.len());
+
+    let read = async
+    {
+        while buf.len() < PREFACE.len() && PREFACE.starts_with(&buf)
+        {
+            let mut chunk = [0; PREFACE.len()];
+            let n = io.read(&mut chunk[..PREFACE.len() - buf.len()]).await?;
+            if n == 0
+            {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    match timeout
+    {
+        Some(timeout) => tokio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "header read timed out"))??,
+        None => read.await?,
+    }
+
+    Ok((buf == PREFACE, buf))
+}
+
+
+//=============================================================================
+// Rewind
+//
+// 先に読み込んだデータを返してから、元のコネクションの読み込みを続ける
+//=============================================================================
+pub(crate) struct Rewind<I>
+{
+    prefix: Vec<u8>,
+    io: I,
+}
+
+impl<I> Rewind<I>
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub(crate) fn new(io: I, prefix: Vec<u8>) -> Self
+    {
+        Self { prefix, io }
+    }
+}
+
+impl<I> AsyncRead for Rewind<I>
+    where
+        I: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>>
+    {
+        if !self.prefix.is_empty()
+        {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<I> AsyncWrite for Rewind<I>
+    where
+        I: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool
+    {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
@@ -2,8 +2,61 @@
 extern crate serde_derive;
 extern crate toml;
 
+mod access_log;
 mod core;
 mod config;
+mod config_error;
+mod database;
+mod error;
+pub mod extract;
+mod http1;
+mod http2;
+pub mod middleware;
+mod request_id;
+pub mod response;
+mod router;
+pub mod server;
+mod shutdown;
+pub mod sse;
+mod tls;
+mod upgrade;
+mod view;
+pub mod websocket;
+
+use axum::body::{ Body, Bytes, HttpBody };
+use axum::handler::Handler;
+use axum::http::{ Request, Response, StatusCode };
+use axum::routing::Route;
+use axum::BoxError;
+
+use crate::config::IbisConfig;
+use crate::error::ErrorPages;
+use crate::router::IbisRouter;
+use crate::server::IbisServer;
+use crate::shutdown::ShutdownHook;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+
+use tower::{ Layer, Service };
+
+use askama::Template;
+
+use serde::de::DeserializeOwned;
+
+pub use axum::routing::MethodFilter;
+pub use crate::config::ConfigMode;
+pub use crate::config_error::ConfigError;
+pub use crate::database::Db;
+pub use crate::error::{ Error, ErrorPage, ErrorType };
+pub use crate::middleware::Middleware;
+pub use crate::request_id::RequestId;
+pub use crate::router::Group;
+pub use crate::shutdown::ShutdownSignal;
+pub use crate::sse::Sse;
+pub use crate::view::View;
+pub use crate::websocket::{ WebSocket, WebSocketUpgrade };
 
 
 //=============================================================================
@@ -11,21 +64,434 @@ mod config;
 //
 // (1) アプリケーション起動の例
 // ```
-// use ibis;
+// use ibis::extract::Path;
+//
+// async fn index() -> &'static str
+// {
+//      "Hello, world"
+// }
+//
+// async fn show_user(Path(id): Path<String>) -> String
+// {
+//      format!("user: {}", id)
+// }
 //
 // fn main()
 // {
-//      ibis::App::run();
+//      ibis::App::new()
+//          .get("/", index)
+//          .get("/users/:id", show_user)
+//          .run();
 // }
 // ```
+//
+// (2) 独自の設定セクションを使う例
+// ```
+// #[derive(Clone, Deserialize)]
+// struct MySettings
+// {
+//      api_key: String,
+// }
+//
+// async fn show_key(State(settings): State<MySettings>) -> String
+// {
+//      settings.api_key
+// }
+//
+// fn main() -> Result<(), ibis::ConfigError>
+// {
+//      let mut app = ibis::App::new();
+//      let _settings: MySettings = app.config("my_section")?;
+//      app.get("/key", show_key).run();
+//      Ok(())
+// }
+// ```
+//
+// (3) ボディをストリームとして読み書きする例
+// ```
+// // リクエストボディ（Content-Length、チャンク形式）をチャンクごとに読み込む
+// async fn upload(RawBody(mut body): RawBody) -> String
+// {
+//      let mut size = 0;
+//      while let Some(Ok(chunk)) = body.data().await
+//      {
+//          size += chunk.len();
+//      }
+//      format!("{} bytes", size)
+// }
+//
+// // 長さの分からないレスポンスはチャンク形式で順に送られる
+// async fn download() -> Response<Body>
+// {
+//      let (mut sender, body) = Body::channel();
+//      tokio::spawn(async move
+//      {
+//          let _ = sender.send_data("first\n".into()).await;
+//          let _ = sender.send_data("second\n".into()).await;
+//      });
+//      Response::new(body)
+// }
+// ```
+//
+// テンプレート（View、エラーページ）のディレクトリはaskamaがコンパイル時に
+// askama.tomlの`dirs`から決めるため、config.tomlでは指定できない。
+// 規約ではviews/に置き、変更する場合はaskama.tomlを書き換えて再ビルドする。
 //=============================================================================
-pub struct App {}
+#[derive(Default)]
+pub struct App
+{
+    router: IbisRouter,
+    config_mode: ConfigMode,
+    config: Option<IbisConfig>,
+    shutdown_hooks: Vec<ShutdownHook>,
+    servers: HashMap<String, Box<dyn IbisServer>>,
+    error_pages: ErrorPages,
+}
 
 impl App
 {
-    pub fn run()
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub fn new() -> Self
     {
-        crate::core::IbisCore::run();
+        Self
+        {
+            router: IbisRouter::new(),
+            config_mode: ConfigMode::default(),
+            config: None,
+            shutdown_hooks: Vec::new(),
+            servers: HashMap::new(),
+            error_pages: ErrorPages::default(),
+        }
+    }
+
+    //=========================================================================
+    // 設定ファイルの読み込みモードを指定
+    //
+    // デフォルトはStrictで、設定に誤りがあれば起動しない。
+    // Lenientを指定すると誤りのあるセクションはデフォルト値で起動する。
+    // 設定はconfig()またはrun()の呼び出し時に読み込まれるので、
+    // それより前に指定すること。
+    //=========================================================================
+    pub fn config_mode(mut self, mode: ConfigMode) -> Self
+    {
+        self.config_mode = mode;
+        self
+    }
+
+    //=========================================================================
+    // 設定ファイルの任意のセクションを読み込み
+    //
+    // 読み込んだ値はハンドラからState<T>として参照できる。
+    //=========================================================================
+    pub fn config<T>(&mut self, section: &str) -> Result<T, ConfigError>
+        where
+            T: DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        let value: T = self.load_config()?.section(section)?;
+        self.router.extension(value.clone());
+        Ok(value)
+    }
+
+    //=========================================================================
+    // ハンドラから参照できる値を追加
+    //
+    // ハンドラではextract::State<T>として受け取る。
+    //=========================================================================
+    pub fn state<T>(mut self, value: T) -> Self
+        where
+            T: Clone + Send + Sync + 'static,
+    {
+        self.router.extension(value);
+        self
+    }
+
+    //=========================================================================
+    // シャットダウン時に実行する処理を追加
+    //
+    // SIGINT/SIGTERMの受信後、処理中のリクエストが終了してから
+    // 登録順に実行される。
+    //=========================================================================
+    pub fn on_shutdown<F, Fut>(mut self, hook: F) -> Self
+        where
+            F: FnOnce() -> Fut + Send + 'static,
+            Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(|| Box::pin(hook())));
+        self
+    }
+
+    //=========================================================================
+    // 独自のサーバを登録
+    //
+    // [server]セクションのkindにkind名を指定すると使われる。
+    //=========================================================================
+    pub fn server<S>(mut self, kind: &str, server: S) -> Self
+        where
+            S: IbisServer + 'static,
+    {
+        self.servers.insert(kind.to_string(), Box::new(server));
+        self
+    }
+
+    //=========================================================================
+    // エラーページのテンプレートを登録
+    //
+    // ルートが見つからない場合やハンドラがエラーを返した（パニックした）場合に、
+    // HTMLを受け付けるクライアントにはテンプレートをレンダリングして返す。
+    // 5xxはステータスのテンプレートがなければ500のテンプレートを使い、
+    // どちらもなければ組み込みの簡単なページを返す。
+    // ```
+    // #[derive(askama::Template)]
+    // #[template(path = "errors/404.html")]
+    // struct NotFoundTemplate
+    // {
+    //      error: ibis::ErrorPage,
+    // }
+    //
+    // ibis::App::new()
+    //      .error_page(StatusCode::NOT_FOUND, |error| NotFoundTemplate { error })
+    //      .run();
+    // ```
+    //=========================================================================
+    pub fn error_page<T, F>(mut self, status: StatusCode, page: F) -> Self
+        where
+            T: Template,
+            F: Fn(ErrorPage) -> T + Send + Sync + 'static,
+    {
+        self.error_pages.add(status, page);
+        self
+    }
+
+    //=========================================================================
+    // 設定ファイルの読み込み（読み込み済みであればそれを返す）
+    //=========================================================================
+    fn load_config(&mut self) -> Result<&IbisConfig, ConfigError>
+    {
+        if self.config.is_none()
+        {
+            self.config = Some(IbisConfig::init(self.config_mode)?);
+        }
+        Ok(self.config.as_ref().expect("config is loaded"))
+    }
+
+    //=========================================================================
+    // GETのルートを追加
+    //=========================================================================
+    pub fn get<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::GET, path, handler)
     }
-}
 
+    //=========================================================================
+    // POSTのルートを追加
+    //=========================================================================
+    pub fn post<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::POST, path, handler)
+    }
+
+    //=========================================================================
+    // PUTのルートを追加
+    //=========================================================================
+    pub fn put<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::PUT, path, handler)
+    }
+
+    //=========================================================================
+    // PATCHのルートを追加
+    //=========================================================================
+    pub fn patch<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::PATCH, path, handler)
+    }
+
+    //=========================================================================
+    // DELETEのルートを追加
+    //=========================================================================
+    pub fn delete<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::DELETE, path, handler)
+    }
+
+    //=========================================================================
+    // HEADのルートを追加
+    //=========================================================================
+    pub fn head<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::HEAD, path, handler)
+    }
+
+    //=========================================================================
+    // OPTIONSのルートを追加
+    //=========================================================================
+    pub fn options<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::OPTIONS, path, handler)
+    }
+
+    //=========================================================================
+    // すべてのメソッドに対するルートを追加
+    //=========================================================================
+    pub fn any<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::all(), path, handler)
+    }
+
+    //=========================================================================
+    // WebSocketのルートを追加
+    //
+    // ハンドシェイクの後、callbackにWebSocketが渡される。
+    //=========================================================================
+    pub fn websocket<F, Fut>(self, path: &str, callback: F) -> Self
+        where
+            F: FnOnce(WebSocket) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.get(path, move |ws: WebSocketUpgrade| async move
+        {
+            ws.on_upgrade(callback)
+        })
+    }
+
+    //=========================================================================
+    // メソッドを指定してルートを追加
+    //=========================================================================
+    pub fn route<H, T>(mut self, filter: MethodFilter, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.router.add(filter, path, handler);
+        self
+    }
+
+    //=========================================================================
+    // ミドルウェアを適用したルートを追加
+    //
+    // ミドルウェアはこのルートにだけ適用され、全体やグループの
+    // ミドルウェアの後に実行される。
+    // ```
+    // ibis::App::new()
+    //      .route_with(MethodFilter::POST, "/upload", upload, RequireToken)
+    // ```
+    //=========================================================================
+    pub fn route_with<H, T, M>(
+        self,
+        filter: MethodFilter,
+        path: &str,
+        handler: H,
+        middleware: M,
+    ) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+            M: Middleware,
+    {
+        self.route(filter, path, handler.layer(crate::middleware::layer(middleware)))
+    }
+
+    //=========================================================================
+    // すべてのルートにミドルウェアを追加
+    //
+    // 先に追加したものほど先にリクエストを処理する。
+    //=========================================================================
+    pub fn middleware<M>(self, middleware: M) -> Self
+        where
+            M: Middleware,
+    {
+        self.layer(crate::middleware::layer(middleware))
+    }
+
+    //=========================================================================
+    // すべてのルートにtowerのLayerを追加
+    //=========================================================================
+    pub fn layer<L, ResBody>(mut self, layer: L) -> Self
+        where
+            L: Layer<Route<Body>> + 'static,
+            L::Service: Service<Request<Body>, Response = Response<ResBody>, Error = Infallible>
+                + Clone
+                + Send
+                + 'static,
+            <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+            ResBody: HttpBody<Data = Bytes> + Send + 'static,
+            ResBody::Error: Into<BoxError>,
+    {
+        self.router.layer(layer);
+        self
+    }
+
+    //=========================================================================
+    // パスのプレフィックスを共有するルートのグループを追加
+    //=========================================================================
+    pub fn group<F>(mut self, prefix: &str, f: F) -> Self
+        where
+            F: FnOnce(Group) -> Group,
+    {
+        self.router.group(prefix, f);
+        self
+    }
+
+    //=========================================================================
+    // アプリケーションの起動
+    //=========================================================================
+    pub fn run(mut self)
+    {
+        // 設定に誤りがあれば起動しない
+        if let Err(e) = self.load_config()
+        {
+            eprintln!("[ERROR] {}", e);
+            std::process::exit(1);
+        }
+
+        let mut config = self.config.take().expect("config is loaded");
+
+        // [server]セクションのkindに応じたサーバ
+        let server = match server::select(&mut config, self.servers, self.config_mode)
+        {
+            Ok(server) => server,
+            Err(e) =>
+            {
+                eprintln!("[ERROR] {}", e);
+                std::process::exit(1);
+            },
+        };
+
+        // WebSocketの設定はWebSocketUpgradeから参照する
+        self.router.extension(config.get_websocket_config().clone());
+
+        crate::core::IbisCore::run(
+            config,
+            server,
+            self.router.into_router(),
+            self.error_pages,
+            self.shutdown_hooks,
+        );
+    }
+}
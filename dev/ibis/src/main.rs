@@ -1,7 +1,57 @@
-use ibis;
+use askama::Template;
+use axum::extract::Path;
+use axum::http::StatusCode;
 
-fn main()
+
+//=============================================================================
+// IndexTemplate
+//=============================================================================
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexTemplate {}
+
+//=============================================================================
+// NotFoundTemplate
+//=============================================================================
+#[derive(Template)]
+#[template(path = "errors/404.html")]
+struct NotFoundTemplate
+{
+    error: ibis::ErrorPage,
+}
+
+//=============================================================================
+// ServerErrorTemplate
+//=============================================================================
+#[derive(Template)]
+#[template(path = "errors/500.html")]
+struct ServerErrorTemplate
 {
-    ibis::App::run();
+    error: ibis::ErrorPage,
 }
 
+//=============================================================================
+// トップページ
+//=============================================================================
+async fn index() -> ibis::View<IndexTemplate>
+{
+    ibis::View::new(IndexTemplate {})
+}
+
+//=============================================================================
+// ユーザの表示
+//=============================================================================
+async fn show_user(Path(id): Path<String>) -> String
+{
+    format!("user: {}", id)
+}
+
+fn main()
+{
+    ibis::App::new()
+        .get("/", index)
+        .get("/users/:id", show_user)
+        .error_page(StatusCode::NOT_FOUND, |error| NotFoundTemplate { error })
+        .error_page(StatusCode::INTERNAL_SERVER_ERROR, |error| ServerErrorTemplate { error })
+        .run();
+}
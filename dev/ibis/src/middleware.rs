@@ -0,0 +1,142 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll };
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::http::Request;
+use axum::response::Response;
+
+use tower::util::BoxCloneService;
+use tower::{ Layer, Service, ServiceExt };
+
+
+//=============================================================================
+// Middleware
+//
+// ハンドラの前後に処理を追加する。next.run()を呼ばずにレスポンスを返すと
+// 以降のミドルウェアとハンドラは実行されない。
+//
+// App::middleware()で全体に、Group::middleware()でグループに、
+// route_with()でルートごとに適用する。
+// リクエストは 全体 → グループ → ルート → ハンドラ の順に処理され、
+// それぞれの中では登録した順に実行される（レスポンスは逆順）。
+// ```
+// struct RequireToken;
+//
+// #[async_trait]
+// impl Middleware for RequireToken
+// {
+//      async fn handle(&self, request: Request<Body>, next: Next) -> Response
+//      {
+//          match request.headers().contains_key("x-token")
+//          {
+//              true => next.run(request).await,
+//              false => StatusCode::UNAUTHORIZED.into_response(),
+//          }
+//      }
+// }
+// ```
+//=============================================================================
+#[async_trait]
+pub trait Middleware: Send + Sync + 'static
+{
+    async fn handle(&self, request: Request<Body>, next: Next) -> Response;
+}
+
+
+//=============================================================================
+// Next
+//
+// 次のミドルウェア、またはハンドラ
+//=============================================================================
+pub struct Next
+{
+    inner: BoxCloneService<Request<Body>, Response, Infallible>,
+}
+
+impl Next
+{
+    //=========================================================================
+    // 次の処理を実行してレスポンスを受け取る
+    //=========================================================================
+    pub async fn run(self, request: Request<Body>) -> Response
+    {
+        match self.inner.oneshot(request).await
+        {
+            Ok(response) => response,
+            Err(e) => match e {},
+        }
+    }
+}
+
+
+//=============================================================================
+// MiddlewareをtowerのLayerに変換
+//=============================================================================
+pub fn layer<M>(middleware: M) -> MiddlewareLayer
+    where
+        M: Middleware,
+{
+    MiddlewareLayer { middleware: Arc::new(middleware) }
+}
+
+
+//=============================================================================
+// MiddlewareLayer
+//=============================================================================
+#[derive(Clone)]
+pub struct MiddlewareLayer
+{
+    middleware: Arc<dyn Middleware>,
+}
+
+impl<S> Layer<S> for MiddlewareLayer
+{
+    type Service = MiddlewareService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service
+    {
+        MiddlewareService
+        {
+            middleware: self.middleware.clone(),
+            inner,
+        }
+    }
+}
+
+
+//=============================================================================
+// MiddlewareService
+//=============================================================================
+#[derive(Clone)]
+pub struct MiddlewareService<S>
+{
+    middleware: Arc<dyn Middleware>,
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for MiddlewareService<S>
+    where
+        S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>
+    {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future
+    {
+        let middleware = self.middleware.clone();
+        let next = Next { inner: BoxCloneService::new(self.inner.clone()) };
+
+        Box::pin(async move { Ok(middleware.handle(request, next).await) })
+    }
+}
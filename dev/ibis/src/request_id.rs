@@ -0,0 +1,154 @@
+use crate::extract::Rejection;
+use crate::middleware::{ self, Middleware, MiddlewareLayer, Next };
+
+use std::fmt;
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{ FromRequest, RequestParts };
+use axum::http::header::HeaderName;
+use axum::http::{ HeaderValue, Request, StatusCode };
+use axum::response::Response;
+
+use rand::RngCore;
+
+use tracing::Instrument;
+
+
+// 受け付けるリクエストIDの最大長
+const MAX_LENGTH: usize = 128;
+
+
+//=============================================================================
+// リクエストIDを付与するLayer
+//
+// リクエストヘッダのIDを使い、なければ生成する。IDはハンドラから
+// RequestIdとして参照でき、同じヘッダでレスポンスに付けて返す。
+// リクエストの処理はIDを持つrequestスパンの中で行われるため、
+// ハンドラでのinfo!()等の出力にもIDが含まれる。
+//=============================================================================
+pub(crate) fn layer(header: HeaderName) -> MiddlewareLayer
+{
+    middleware::layer(RequestIdMiddleware { header })
+}
+
+
+//=============================================================================
+// RequestId
+//
+// リクエストを識別するID
+// ```
+// async fn index(request_id: RequestId) -> String
+// {
+//      format!("request: {}", request_id)
+// }
+// ```
+//=============================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId
+{
+    //=========================================================================
+    // 文字列として取得
+    //=========================================================================
+    pub fn as_str(&self) -> &str
+    {
+        &self.0
+    }
+
+    //=========================================================================
+    // UUID（バージョン4）の形式で生成
+    //=========================================================================
+    fn generate() -> Self
+    {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        Self(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32],
+        ))
+    }
+
+    //=========================================================================
+    // リクエストヘッダの値から作成
+    //
+    // ログを壊さないように、空白や制御文字を含む値や長すぎる値は使わない。
+    //=========================================================================
+    fn from_header(value: &HeaderValue) -> Option<Self>
+    {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LENGTH
+            && value.bytes().all(|b| b.is_ascii_graphic());
+
+        valid.then(|| Self(value.to_string()))
+    }
+}
+
+impl fmt::Display for RequestId
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.write_str(&self.0)
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for RequestId
+    where
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        match req.extensions().get::<RequestId>()
+        {
+            Some(request_id) => Ok(request_id.clone()),
+            None => Err(Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "request id is not available",
+            )),
+        }
+    }
+}
+
+
+//=============================================================================
+// RequestIdMiddleware
+//=============================================================================
+struct RequestIdMiddleware
+{
+    header: HeaderName,
+}
+
+#[async_trait]
+impl Middleware for RequestIdMiddleware
+{
+    async fn handle(&self, mut request: Request<Body>, next: Next) -> Response
+    {
+        let request_id = request.headers()
+            .get(&self.header)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        request.extensions_mut().insert(request_id.clone());
+
+        let span = tracing::info_span!("request", request_id = %request_id);
+        let mut response = next.run(request).instrument(span).await;
+
+        if let Ok(value) = HeaderValue::from_str(request_id.as_str())
+        {
+            response.headers_mut().entry(&self.header).or_insert(value);
+        }
+        response
+    }
+}
@@ -0,0 +1,421 @@
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+
+use axum::body::{ boxed, Bytes, Empty as EmptyBody, Full, HttpBody };
+use axum::http::{ header, HeaderMap, HeaderValue, StatusCode };
+
+use hyper::body::SizeHint;
+
+use tokio::fs;
+use tokio::io::{ AsyncRead, ReadBuf };
+
+use serde::Serialize;
+
+use tracing::error;
+
+pub use axum::response::{ IntoResponse, Response };
+pub use crate::extract::Json;
+
+
+// ファイルを読み込んで送るチャンクのサイズ
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+
+//=============================================================================
+// ハンドラの戻り値として使うレスポンス
+//
+// IntoResponseを実装した型はハンドラからそのまま返せる。
+// Result<T, E>はTとEの両方が実装していれば、OkとErrのどちらも
+// レスポンスに変換される。ステータスを変えるには(StatusCode, T)を返す。
+// ```
+// #[derive(Serialize)]
+// struct User
+// {
+//      id: u64,
+//      name: String,
+// }
+//
+// async fn create_user() -> (StatusCode, Json<User>)
+// {
+//      (StatusCode::CREATED, Json(User { id: 1, name: "ibis".to_string() }))
+// }
+//
+// async fn show_user(Path(id): Path<u64>) -> Result<Json<User>, StatusCode>
+// {
+//      find_user(id).map(Json).ok_or(StatusCode::NOT_FOUND)
+// }
+//
+// async fn old_page() -> Redirect
+// {
+//      Redirect::permanent("/new-page")
+// }
+// ```
+//=============================================================================
+
+
+//=============================================================================
+// 本文とContent-Typeからレスポンスを作成
+//
+// Content-Lengthは本文の長さからサーバが付ける。
+//=============================================================================
+fn with_content_type<T>(body: T, content_type: &'static str) -> Response
+    where
+        T: Into<Full<Bytes>>,
+{
+    let mut response = Response::new(boxed(body.into()));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type)
+    );
+    response
+}
+
+
+//=============================================================================
+// Json
+//
+// application/jsonのレスポンス
+//=============================================================================
+impl<T> IntoResponse for Json<T>
+    where
+        T: Serialize,
+{
+    fn into_response(self) -> Response
+    {
+        match serde_json::to_vec(&self.0)
+        {
+            Ok(json) => with_content_type(json, "application/json"),
+            Err(e) =>
+            {
+                // シリアライズに失敗したら500を返す
+                error!("failed to serialize json: {}", e);
+                crate::error::framework_error(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            },
+        }
+    }
+}
+
+
+//=============================================================================
+// Text
+//
+// text/plainのレスポンス
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Text<T>(pub T);
+
+impl<T> IntoResponse for Text<T>
+    where
+        T: Into<String>,
+{
+    fn into_response(self) -> Response
+    {
+        with_content_type(self.0.into(), "text/plain; charset=utf-8")
+    }
+}
+
+
+//=============================================================================
+// Html
+//
+// text/htmlのレスポンス（テンプレートを使う場合はViewを使う）
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Html<T>(pub T);
+
+impl<T> IntoResponse for Html<T>
+    where
+        T: Into<String>,
+{
+    fn into_response(self) -> Response
+    {
+        with_content_type(self.0.into(), "text/html; charset=utf-8")
+    }
+}
+
+
+//=============================================================================
+// Empty
+//
+// 本文のないレスポンス
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+pub struct Empty(pub StatusCode);
+
+impl Empty
+{
+    //=========================================================================
+    // 204 No Content
+    //=========================================================================
+    pub fn no_content() -> Self
+    {
+        Self(StatusCode::NO_CONTENT)
+    }
+}
+
+impl IntoResponse for Empty
+{
+    fn into_response(self) -> Response
+    {
+        let mut response = Response::new(boxed(EmptyBody::new()));
+        *response.status_mut() = self.0;
+        response
+    }
+}
+
+
+//=============================================================================
+// Redirect
+//
+// Locationヘッダを付けたリダイレクト
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct Redirect
+{
+    status: StatusCode,
+    location: String,
+}
+
+impl Redirect
+{
+    //=========================================================================
+    // 301 Moved Permanently
+    //=========================================================================
+    pub fn moved_permanently(uri: &str) -> Self
+    {
+        Self::with_status(StatusCode::MOVED_PERMANENTLY, uri)
+    }
+
+    //=========================================================================
+    // 302 Found
+    //=========================================================================
+    pub fn found(uri: &str) -> Self
+    {
+        Self::with_status(StatusCode::FOUND, uri)
+    }
+
+    //=========================================================================
+    // 303 See Other（POSTの後にGETで表示するページ）
+    //=========================================================================
+    pub fn see_other(uri: &str) -> Self
+    {
+        Self::with_status(StatusCode::SEE_OTHER, uri)
+    }
+
+    //=========================================================================
+    // 307 Temporary Redirect（メソッドとボディを変えない）
+    //=========================================================================
+    pub fn temporary(uri: &str) -> Self
+    {
+        Self::with_status(StatusCode::TEMPORARY_REDIRECT, uri)
+    }
+
+    //=========================================================================
+    // 308 Permanent Redirect（メソッドとボディを変えない）
+    //=========================================================================
+    pub fn permanent(uri: &str) -> Self
+    {
+        Self::with_status(StatusCode::PERMANENT_REDIRECT, uri)
+    }
+
+    //=========================================================================
+    // ステータスを指定して作成
+    //=========================================================================
+    fn with_status(status: StatusCode, uri: &str) -> Self
+    {
+        Self
+        {
+            status,
+            location: uri.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for Redirect
+{
+    fn into_response(self) -> Response
+    {
+        match HeaderValue::try_from(self.location)
+        {
+            Ok(location) =>
+            {
+                let mut response = Empty(self.status).into_response();
+                response.headers_mut().insert(header::LOCATION, location);
+                response
+            },
+            Err(e) =>
+            {
+                error!("invalid redirect location: {}", e);
+                crate::error::framework_error(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            },
+        }
+    }
+}
+
+
+//=============================================================================
+// File
+//
+// ファイルの内容を拡張子に応じたContent-Typeで返す。
+// 内容は一度に読み込まず、チャンクごとに読みながら送る。
+// ```
+// async fn logo() -> Result<File, StatusCode>
+// {
+//      File::open("public/logo.png").await.map_err(|_| StatusCode::NOT_FOUND)
+// }
+// ```
+//=============================================================================
+#[derive(Debug)]
+pub struct File
+{
+    file: fs::File,
+    length: u64,
+    content_type: &'static str,
+}
+
+impl File
+{
+    //=========================================================================
+    // ファイルを開く
+    //=========================================================================
+    pub async fn open<P>(path: P) -> io::Result<Self>
+        where
+            P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let file = fs::File::open(path).await?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file()
+        {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not a regular file"));
+        }
+
+        Ok(Self
+        {
+            file,
+            length: metadata.len(),
+            content_type: content_type(path),
+        })
+    }
+}
+
+impl IntoResponse for File
+{
+    fn into_response(self) -> Response
+    {
+        let body = FileBody
+        {
+            file: self.file,
+            remaining: self.length,
+            buf: vec![0; FILE_CHUNK_SIZE].into_boxed_slice(),
+        };
+
+        let mut response = Response::new(boxed(body));
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(self.content_type));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(self.length));
+        response
+    }
+}
+
+
+//=============================================================================
+// FileBody
+//
+// ファイルをチャンクごとに読み込むボディ。開いた後にファイルが
+// 短くなった場合は、Content-Lengthに満たないためエラーにする。
+//=============================================================================
+struct FileBody
+{
+    file: fs::File,
+    remaining: u64,
+    buf: Box<[u8]>,
+}
+
+impl HttpBody for FileBody
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Self::Data, Self::Error>>>
+    {
+        if self.remaining == 0
+        {
+            return Poll::Ready(None);
+        }
+
+        let this = &mut *self;
+        let length = this.buf.len().min(this.remaining as usize);
+        let mut buf = ReadBuf::new(&mut this.buf[..length]);
+        match Pin::new(&mut this.file).poll_read(cx, &mut buf)
+        {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        let read = buf.filled();
+        if read.is_empty()
+        {
+            return Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file is shorter than its length",
+            ))));
+        }
+        this.remaining -= read.len() as u64;
+        Poll::Ready(Some(Ok(Bytes::copy_from_slice(read))))
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, _cx: &mut Context<'_>)
+        -> Poll<Result<Option<HeaderMap>, Self::Error>>
+    {
+        Poll::Ready(Ok(None))
+    }
+
+    fn is_end_stream(&self) -> bool
+    {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint
+    {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+
+//=============================================================================
+// 拡張子からContent-Typeを決める
+//=============================================================================
+fn content_type(path: &Path) -> &'static str
+{
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str()
+    {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
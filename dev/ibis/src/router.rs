@@ -0,0 +1,522 @@
+use crate::middleware::Middleware;
+
+use std::convert::Infallible;
+
+use axum::body::{ Body, Bytes, HttpBody };
+use axum::handler::{ Handler, IntoService };
+use axum::http::{ header, Request, Response, StatusCode, Uri };
+use axum::response::IntoResponse;
+use axum::routing::{ MethodFilter, MethodRouter, Route };
+use axum::{ BoxError, Extension, Router };
+
+use tower::{ Layer, Service };
+
+
+// Routerに後から適用する処理
+type RouterFn = Box<dyn FnOnce(Router<Body>) -> Router<Body>>;
+
+
+//=============================================================================
+// IbisRouter
+//
+// パスごとにメソッドとハンドラの組を保持し、最終的にaxumのRouterへ変換する。
+// パスのパターンはaxumの記法に従う（`:name`で名前付きパラメータ、
+// `*name`でワイルドカード）。
+//=============================================================================
+#[derive(Default)]
+pub(crate) struct IbisRouter
+{
+    routes: Vec<(String, MethodRouter<Body>, MethodFilter)>,
+    groups: Vec<(String, Router<Body>)>,
+    problem_prefixes: Vec<String>,
+    layers: Vec<RouterFn>,
+    extensions: Vec<RouterFn>,
+}
+
+impl IbisRouter
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub(crate) fn new() -> Self
+    {
+        Self::default()
+    }
+
+    //=========================================================================
+    // ルートの追加
+    //
+    // 同じパスが登録済みであれば、そのパスのメソッドとして追加する。
+    //=========================================================================
+    pub(crate) fn add<H, T>(&mut self, filter: MethodFilter, path: &str, handler: H)
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        match self.routes.iter_mut().find(|(p, _, _)| p == path)
+        {
+            Some((_, method_router, allowed)) =>
+            {
+                let current = std::mem::take(method_router);
+                *method_router = current.on(filter, handler);
+                *allowed |= filter;
+            },
+            None =>
+            {
+                let method_router = MethodRouter::new().on(filter, handler);
+                self.routes.push((path.to_string(), method_router, filter));
+            },
+        }
+    }
+
+    //=========================================================================
+    // パスのプレフィックスの下にグループを追加
+    //=========================================================================
+    pub(crate) fn group<F>(&mut self, prefix: &str, f: F)
+        where
+            F: FnOnce(Group) -> Group,
+    {
+        let group = f(Group::default());
+        let (router, problem_prefixes) = group.router.build(false);
+
+        self.problem_prefixes.extend(
+            problem_prefixes.into_iter().map(|inner| format!("{}{}", prefix, inner))
+        );
+        if group.problem_details
+        {
+            self.problem_prefixes.push(prefix.to_string());
+        }
+        self.groups.push((prefix.to_string(), router));
+    }
+
+    //=========================================================================
+    // すべてのルートにLayerを追加
+    //
+    // 先に追加したLayerほど外側になる（リクエストを先に処理する）。
+    //=========================================================================
+    pub(crate) fn layer<L, ResBody>(&mut self, layer: L)
+        where
+            L: Layer<Route<Body>> + 'static,
+            L::Service: Service<Request<Body>, Response = Response<ResBody>, Error = Infallible>
+                + Clone
+                + Send
+                + 'static,
+            <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+            ResBody: HttpBody<Data = Bytes> + Send + 'static,
+            ResBody::Error: Into<BoxError>,
+    {
+        self.layers.push(Box::new(|router| router.layer(layer)));
+    }
+
+    //=========================================================================
+    // すべてのハンドラから参照できる値を追加
+    //=========================================================================
+    pub(crate) fn extension<T>(&mut self, value: T)
+        where
+            T: Clone + Send + Sync + 'static,
+    {
+        self.extensions.push(Box::new(|router| router.layer(Extension(value))));
+    }
+
+    //=========================================================================
+    // axumのRouterへ変換
+    //
+    // 一致するパスがなければ404、メソッドが一致しなければ
+    // Allowヘッダ付きの405を返す。Extensionはミドルウェアからも
+    // 参照できるように、Layerより外側に追加する。
+    //=========================================================================
+    pub(crate) fn into_router(self) -> Router<Body>
+    {
+        // サーバが直接返すエラーもグループに応じた形式にする
+        let (router, problem_prefixes) = self.build(true);
+        crate::error::set_problem_prefixes(problem_prefixes);
+        router
+    }
+
+    //=========================================================================
+    // ルート、グループ、Layer、Extensionを組み立てる
+    //
+    // axumではグループのRouterに独自の404を設定できないため、
+    // 一致しないパスは最上位のRouterでまとめて処理する。グループでは
+    // problem+jsonを返すプレフィックスを返す。
+    //=========================================================================
+    fn build(self, root: bool) -> (Router<Body>, Vec<String>)
+    {
+        let router = self.routes
+            .into_iter()
+            .fold(Router::new(), |router, (path, method_router, allowed)|
+            {
+                router.route(&path, method_router.fallback(method_not_allowed(allowed)))
+            });
+
+        let router = self.groups
+            .into_iter()
+            .fold(router, |router, (prefix, group)| router.nest(&prefix, group));
+
+        let router = match root
+        {
+            true => router.fallback(not_found(self.problem_prefixes.clone())),
+            false => router,
+        };
+
+        let router = self.layers
+            .into_iter()
+            .rev()
+            .fold(router, |router, layer| layer(router));
+
+        let router = self.extensions
+            .into_iter()
+            .fold(router, |router, extension| extension(router));
+
+        (router, self.problem_prefixes)
+    }
+}
+
+
+//=============================================================================
+// 404を返すサービス（problem+jsonを返すグループの下のパスには目印を付ける）
+//=============================================================================
+fn not_found(problem_prefixes: Vec<String>) -> IntoService<impl Handler<(Uri,), Body>, (Uri,), Body>
+{
+    let handler = move |uri: Uri| async move
+    {
+        let response = crate::Error::simple("not_found").into_response();
+        let in_group = problem_prefixes.iter()
+            .any(|prefix| crate::error::is_under(uri.path(), prefix));
+
+        match in_group
+        {
+            true => crate::error::problem_details(response),
+            false => response,
+        }
+    };
+    handler.into_service()
+}
+
+
+//=============================================================================
+// 405を返すサービス（Allowヘッダに受け付けるメソッドを並べる）
+//=============================================================================
+fn method_not_allowed(allowed: MethodFilter) -> IntoService<impl Handler<(), Body>, (), Body>
+{
+    const METHODS: &[(MethodFilter, &str)] = &[
+        (MethodFilter::GET, "GET"),
+        (MethodFilter::HEAD, "HEAD"),
+        (MethodFilter::POST, "POST"),
+        (MethodFilter::PUT, "PUT"),
+        (MethodFilter::PATCH, "PATCH"),
+        (MethodFilter::DELETE, "DELETE"),
+        (MethodFilter::OPTIONS, "OPTIONS"),
+        (MethodFilter::TRACE, "TRACE"),
+    ];
+
+    // GETを受け付けるルートはHEADも受け付ける
+    let allowed = match allowed.contains(MethodFilter::GET)
+    {
+        true => allowed | MethodFilter::HEAD,
+        false => allowed,
+    };
+    let allow = METHODS.iter()
+        .filter(|(filter, _)| allowed.contains(*filter))
+        .map(|(_, method)| *method)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let handler = move || async move
+    {
+        crate::error::framework_error(
+            (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, allow)]).into_response()
+        )
+    };
+    handler.into_service()
+}
+
+
+//=============================================================================
+// Group
+//
+// 共通のパスのプレフィックスとミドルウェアを持つルートのまとまり。
+// App::group()に渡したクロージャの中でルートを追加する。
+// ```
+// ibis::App::new()
+//      .group("/admin", |group| group
+//          .middleware(RequireToken)
+//          .get("/users", list_users)
+//          .delete("/users/:id", delete_user))
+//      .run();
+// ```
+//=============================================================================
+#[derive(Default)]
+pub struct Group
+{
+    router: IbisRouter,
+    problem_details: bool,
+}
+
+impl Group
+{
+    //=========================================================================
+    // GETのルートを追加
+    //=========================================================================
+    pub fn get<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::GET, path, handler)
+    }
+
+    //=========================================================================
+    // POSTのルートを追加
+    //=========================================================================
+    pub fn post<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::POST, path, handler)
+    }
+
+    //=========================================================================
+    // PUTのルートを追加
+    //=========================================================================
+    pub fn put<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::PUT, path, handler)
+    }
+
+    //=========================================================================
+    // PATCHのルートを追加
+    //=========================================================================
+    pub fn patch<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::PATCH, path, handler)
+    }
+
+    //=========================================================================
+    // DELETEのルートを追加
+    //=========================================================================
+    pub fn delete<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::DELETE, path, handler)
+    }
+
+    //=========================================================================
+    // HEADのルートを追加
+    //=========================================================================
+    pub fn head<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::HEAD, path, handler)
+    }
+
+    //=========================================================================
+    // OPTIONSのルートを追加
+    //=========================================================================
+    pub fn options<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::OPTIONS, path, handler)
+    }
+
+    //=========================================================================
+    // すべてのメソッドに対するルートを追加
+    //=========================================================================
+    pub fn any<H, T>(self, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.route(MethodFilter::all(), path, handler)
+    }
+
+    //=========================================================================
+    // メソッドを指定してルートを追加
+    //=========================================================================
+    pub fn route<H, T>(mut self, filter: MethodFilter, path: &str, handler: H) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+    {
+        self.router.add(filter, path, handler);
+        self
+    }
+
+    //=========================================================================
+    // ミドルウェアを適用したルートを追加
+    //
+    // ミドルウェアはこのルートにだけ適用され、全体やグループの
+    // ミドルウェアの後に実行される。
+    // ```
+    // group
+    //      .route_with(MethodFilter::POST, "/upload", upload, RequireToken)
+    // ```
+    //=========================================================================
+    pub fn route_with<H, T, M>(
+        self,
+        filter: MethodFilter,
+        path: &str,
+        handler: H,
+        middleware: M,
+    ) -> Self
+        where
+            H: Handler<T, Body>,
+            T: 'static,
+            M: Middleware,
+    {
+        self.route(filter, path, handler.layer(crate::middleware::layer(middleware)))
+    }
+
+    //=========================================================================
+    // グループ内のルートにミドルウェアを追加
+    //=========================================================================
+    pub fn middleware<M>(self, middleware: M) -> Self
+        where
+            M: Middleware,
+    {
+        self.layer(crate::middleware::layer(middleware))
+    }
+
+    //=========================================================================
+    // グループ内のルートにtowerのLayerを追加
+    //=========================================================================
+    pub fn layer<L, ResBody>(mut self, layer: L) -> Self
+        where
+            L: Layer<Route<Body>> + 'static,
+            L::Service: Service<Request<Body>, Response = Response<ResBody>, Error = Infallible>
+                + Clone
+                + Send
+                + 'static,
+            <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+            ResBody: HttpBody<Data = Bytes> + Send + 'static,
+            ResBody::Error: Into<BoxError>,
+    {
+        self.router.layer(layer);
+        self
+    }
+
+    //=========================================================================
+    // グループ内のエラーをAcceptヘッダに関わらずproblem+jsonで返す
+    //=========================================================================
+    pub fn problem_details(mut self) -> Self
+    {
+        self.problem_details = true;
+        self.layer(crate::error::problem_details_layer())
+    }
+
+    //=========================================================================
+    // グループの中にグループを追加
+    //=========================================================================
+    pub fn group<F>(mut self, prefix: &str, f: F) -> Self
+        where
+            F: FnOnce(Group) -> Group,
+    {
+        self.router.group(prefix, f);
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use axum::http::Method;
+
+    use tower::ServiceExt;
+
+
+    //=========================================================================
+    // リクエストを1つ処理してステータスとボディを返す
+    //=========================================================================
+    async fn call(router: IbisRouter, method: Method, path: &str) -> (StatusCode, Option<String>, String)
+    {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.into_router().oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let allow = response.headers()
+            .get(header::ALLOW)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, allow, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    //=========================================================================
+    // テスト用のルート（同じパスにGETとPOSTを別々に追加する）
+    //=========================================================================
+    fn router() -> IbisRouter
+    {
+        let mut router = IbisRouter::new();
+        router.add(MethodFilter::GET, "/users", || async { "list" });
+        router.add(MethodFilter::DELETE, "/users/:id", || async { "delete" });
+        router.add(MethodFilter::POST, "/users", || async { "create" });
+        router
+    }
+
+
+    //=========================================================================
+    // 同じパスに追加したメソッドはどちらも呼び出せる
+    //=========================================================================
+    #[tokio::test]
+    async fn merges_methods_on_same_path()
+    {
+        let (status, _, body) = call(router(), Method::GET, "/users").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "list");
+
+        let (status, _, body) = call(router(), Method::POST, "/users").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "create");
+
+        // GETのルートはHEADにも応答する
+        let (status, _, body) = call(router(), Method::HEAD, "/users").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "");
+    }
+
+    //=========================================================================
+    // 一致するパスがなければ404
+    //=========================================================================
+    #[tokio::test]
+    async fn unknown_path_returns_404()
+    {
+        let (status, allow, _) = call(router(), Method::GET, "/posts").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(allow, None);
+    }
+
+    //=========================================================================
+    // メソッドが一致しなければAllowヘッダ付きの405
+    //=========================================================================
+    #[tokio::test]
+    async fn unknown_method_returns_405_with_allow()
+    {
+        // GETを受け付けるパスにはHEADも含める
+        let (status, allow, _) = call(router(), Method::PUT, "/users").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow.as_deref(), Some("GET,HEAD,POST"));
+
+        let (status, allow, _) = call(router(), Method::GET, "/users/1").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(allow.as_deref(), Some("DELETE"));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::{ ConfigMode, IbisConfig, IbisServerHyperConfig, IbisServerTokioConfig, IbisServerType };
+use crate::config_error::ConfigError;
+use crate::http1::{ self, Http1Config };
+use crate::http2::{ self, Rewind };
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Router;
+
+use hyper::server::conn::Http;
+
+use tokio::io::{ AsyncRead, AsyncWrite };
+
+use tower::ServiceExt;
+
+use tracing::{ debug, info };
+
+pub use crate::shutdown::ShutdownSignal;
+
+
+//=============================================================================
+// Io
+//
+// サーバに渡すコネクション（TCPのソケットなど）
+//=============================================================================
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T> Io for T
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{}
+
+pub type BoxIo = Box<dyn Io>;
+
+// コネクションを処理するFuture
+pub type ServeFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+
+//=============================================================================
+// IbisServer
+//
+// 受け付けたコネクションを処理するサーバの実装。
+// [server]セクションのkindで選択する。独自のサーバはApp::server()で登録し、
+// [kind名]のセクションがconfigure()に渡される。
+//
+// コネクションの処理中はShutdownSignalを保持し、シャットダウンが
+// 通知されたら新しいリクエストの受付を止めてコネクションを閉じること。
+// リクエストのextensionsにConnectInfoとShutdownSignalを追加しておくと、
+// ハンドラから接続先やシャットダウンの通知を参照できる（WebSocketなど）。
+//=============================================================================
+pub trait IbisServer: Send + Sync
+{
+    //=========================================================================
+    // 設定の読み込み
+    //=========================================================================
+    fn configure(&mut self, _options: &toml::Value) -> Result<(), ConfigError>
+    {
+        Ok(())
+    }
+
+    //=========================================================================
+    // TLSのALPNで提示するプロトコル
+    //=========================================================================
+    fn alpn_protocols(&self) -> Vec<&'static str>
+    {
+        vec!["http/1.1"]
+    }
+
+    //=========================================================================
+    // 1つのコネクションを処理する
+    //=========================================================================
+    fn serve_connection(
+        &self,
+        io: BoxIo,
+        peer: SocketAddr,
+        router: Router<Body>,
+        shutdown: ShutdownSignal,
+    ) -> ServeFuture;
+}
+
+
+//=============================================================================
+// 設定に応じたサーバを選択
+//
+// kindのサーバが登録されていなければ、Strictモードではエラーを返し、
+// Lenientモードでは警告を記録して[tokio]セクションの設定のTokioServerを使う。
+//=============================================================================
+pub(crate) fn select(
+    config: &mut IbisConfig,
+    mut servers: HashMap<String, Box<dyn IbisServer>>,
+    mode: ConfigMode,
+) -> Result<Arc<dyn IbisServer>, ConfigError>
+{
+    let custom_config = match &config.server_config
+    {
+        IbisServerType::Tokio(tokio_config) => return Ok(Arc::new(TokioServer::new(tokio_config))),
+        IbisServerType::Hyper(hyper_config) => return Ok(Arc::new(HyperServer::new(hyper_config))),
+        IbisServerType::Custom(custom_config) => custom_config,
+    };
+
+    if let Some(mut server) = servers.remove(&custom_config.kind)
+    {
+        server.configure(&custom_config.options)?;
+        return Ok(Arc::from(server));
+    }
+
+    let e = ConfigError::UnknownKind
+    {
+        section: "server".to_string(),
+        kind: custom_config.kind.clone(),
+    };
+    match mode
+    {
+        ConfigMode::Strict => Err(e),
+        ConfigMode::Lenient =>
+        {
+            let server = Arc::new(TokioServer::new(&custom_config.tokio_config));
+            config.warnings.push(e);
+            Ok(server)
+        },
+    }
+}
+
+
+//=============================================================================
+// TokioServer
+//
+// ibisのHTTP/1.1の実装でコネクションを処理する。HTTP/2のプリフェイスで
+// 始まるコネクション（ALPNのh2、h2cのprior knowledge）はhyperで処理する。
+//=============================================================================
+pub(crate) struct TokioServer
+{
+    http1: Http1Config,
+    http2: Http,
+}
+
+impl TokioServer
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub(crate) fn new(config: &IbisServerTokioConfig) -> Self
+    {
+        let mut http2 = Http::new();
+        http2.http2_only(true);
+        http2_settings(
+            &mut http2,
+            config.http2_max_concurrent_streams,
+            config.http2_initial_window_size,
+            config.http2_max_header_list_size,
+        );
+
+        Self
+        {
+            http1: Http1Config::new(config),
+            http2,
+        }
+    }
+}
+
+impl IbisServer for TokioServer
+{
+    fn alpn_protocols(&self) -> Vec<&'static str>
+    {
+        vec!["h2", "http/1.1"]
+    }
+
+    fn serve_connection(
+        &self,
+        mut io: BoxIo,
+        peer: SocketAddr,
+        router: Router<Body>,
+        mut shutdown: ShutdownSignal,
+    ) -> ServeFuture
+    {
+        let http1 = self.http1.clone();
+        let http2 = self.http2.clone();
+
+        Box::pin(async move
+        {
+            // 先頭のデータでHTTP/1.1とHTTP/2を判別
+            let detected = tokio::select!
+            {
+                detected = http2::detect(&mut io, http1.header_read_timeout()) => detected,
+                _ = shutdown.recv() => return,
+            };
+            let (is_http2, prefix) = match detected
+            {
+                Ok(detected) => detected,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    info!("connection from {} closed: {}", peer, e);
+                    return;
+                },
+                Err(e) =>
+                {
+                    debug!("failed to read from {}: {}", peer, e);
+                    return;
+                },
+            };
+
+            let io = Rewind::new(io, prefix);
+            match is_http2
+            {
+                true => serve_hyper(http2, Box::new(io), peer, router, shutdown).await,
+                false => http1::serve_connection(io, peer, http1, router, shutdown).await,
+            }
+        })
+    }
+}
+
+
+//=============================================================================
+// HyperServer
+//
+// hyperでコネクションを処理する（HTTP/1とHTTP/2）
+//=============================================================================
+pub(crate) struct HyperServer
+{
+    http: Http,
+    alpn_protocols: Vec<&'static str>,
+}
+
+impl HyperServer
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub(crate) fn new(config: &IbisServerHyperConfig) -> Self
+    {
+        let mut http = Http::new();
+        http.http1_keep_alive(config.http1_keep_alive)
+            .http1_only(config.http1_only)
+            .http2_only(config.http2_only);
+
+        if config.http1_header_read_timeout > 0
+        {
+            http.http1_header_read_timeout(
+                Duration::from_secs(config.http1_header_read_timeout)
+            );
+        }
+        if config.max_buf_size > 0
+        {
+            http.max_buf_size(config.max_buf_size);
+        }
+        http2_settings(
+            &mut http,
+            config.http2_max_concurrent_streams,
+            config.http2_initial_window_size,
+            config.http2_max_header_list_size,
+        );
+
+        let alpn_protocols = match (config.http1_only, config.http2_only)
+        {
+            (true, _) => vec!["http/1.1"],
+            (false, true) => vec!["h2"],
+            (false, false) => vec!["h2", "http/1.1"],
+        };
+
+        Self { http, alpn_protocols }
+    }
+}
+
+impl IbisServer for HyperServer
+{
+    fn alpn_protocols(&self) -> Vec<&'static str>
+    {
+        self.alpn_protocols.clone()
+    }
+
+    fn serve_connection(
+        &self,
+        io: BoxIo,
+        peer: SocketAddr,
+        router: Router<Body>,
+        shutdown: ShutdownSignal,
+    ) -> ServeFuture
+    {
+        Box::pin(serve_hyper(self.http.clone(), io, peer, router, shutdown))
+    }
+}
+
+
+//=============================================================================
+// HTTP/2の設定
+//=============================================================================
+fn http2_settings(
+    http: &mut Http,
+    max_concurrent_streams: u32,
+    initial_window_size: u32,
+    max_header_list_size: u32,
+)
+{
+    http.http2_max_concurrent_streams(max_concurrent_streams)
+        .http2_initial_stream_window_size(initial_window_size)
+        .http2_max_header_list_size(max_header_list_size);
+}
+
+
+//=============================================================================
+// hyperでコネクションを処理する
+//=============================================================================
+async fn serve_hyper(
+    http: Http,
+    io: BoxIo,
+    peer: SocketAddr,
+    router: Router<Body>,
+    mut shutdown: ShutdownSignal,
+)
+{
+    // 接続先情報とシャットダウンの通知をリクエストに追加
+    let signal = shutdown.clone();
+    let service = router.map_request(move |mut request: Request<Body>|
+    {
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request.extensions_mut().insert(signal.clone());
+        request
+    });
+
+    let connection = http.serve_connection(io, service).with_upgrades();
+    tokio::pin!(connection);
+
+    // シャットダウンが通知されたら処理中のリクエストを終えて閉じる
+    let result = tokio::select!
+    {
+        result = &mut connection => result,
+        _ = shutdown.recv() =>
+        {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        },
+    };
+
+    if let Err(e) = result
+    {
+        debug!("connection error from {}: {}", peer, e);
+    }
+}
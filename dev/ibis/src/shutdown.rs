@@ -0,0 +1,179 @@
+use crate::extract::Rejection;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::extract::{ FromRequest, RequestParts };
+use axum::http::StatusCode;
+
+use tokio::sync::{ mpsc, watch };
+
+use tracing::info;
+
+
+// シャットダウン時に実行する処理
+pub(crate) type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+
+//=============================================================================
+// Shutdown
+//
+// シャットダウンの通知と、処理中のコネクションの終了待ちを管理する。
+//=============================================================================
+pub(crate) struct Shutdown
+{
+    notify: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+    done_sender: mpsc::Sender<()>,
+    done_receiver: mpsc::Receiver<()>,
+}
+
+impl Shutdown
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub(crate) fn new() -> Self
+    {
+        let (notify, receiver) = watch::channel(false);
+        let (done_sender, done_receiver) = mpsc::channel(1);
+
+        Self
+        {
+            notify,
+            receiver,
+            done_sender,
+            done_receiver,
+        }
+    }
+
+    //=========================================================================
+    // コネクションごとに保持するシグナルを作成
+    //
+    // すべてのシグナルがdropされるまでdrain()は終了しない。
+    //=========================================================================
+    pub(crate) fn signal(&self) -> ShutdownSignal
+    {
+        ShutdownSignal
+        {
+            receiver: self.receiver.clone(),
+            _done: self.done_sender.clone(),
+        }
+    }
+
+    //=========================================================================
+    // シャットダウンを通知し、コネクションの終了を待つ
+    //
+    // timeoutまでにすべてのコネクションが終了すればtrueを返す。
+    //=========================================================================
+    pub(crate) async fn drain(self, timeout: Duration) -> bool
+    {
+        let Self { notify, receiver, done_sender, mut done_receiver } = self;
+
+        let _ = notify.send(true);
+        drop(receiver);
+        drop(done_sender);
+
+        tokio::time::timeout(timeout, done_receiver.recv()).await.is_ok()
+    }
+}
+
+
+//=============================================================================
+// ShutdownSignal
+//
+// シャットダウンの通知を受け取る。保持している間はコネクションが
+// 処理中とみなされる。ハンドラの引数に指定すると、SSEなどの長く続く
+// レスポンスをシャットダウン時に終了できる。
+//=============================================================================
+#[derive(Clone)]
+pub struct ShutdownSignal
+{
+    receiver: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+}
+
+impl ShutdownSignal
+{
+    //=========================================================================
+    // シャットダウンが通知されたかどうか
+    //=========================================================================
+    pub fn is_shutdown(&self) -> bool
+    {
+        *self.receiver.borrow()
+    }
+
+    //=========================================================================
+    // シャットダウンが通知されるまで待つ
+    //=========================================================================
+    pub async fn recv(&mut self)
+    {
+        while !*self.receiver.borrow()
+        {
+            if self.receiver.changed().await.is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+
+#[async_trait]
+impl<B> FromRequest<B> for ShutdownSignal
+    where
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        // サーバがリクエストに追加していなければ使えない
+        match req.extensions().get::<ShutdownSignal>()
+        {
+            Some(shutdown) => Ok(shutdown.clone()),
+            None => Err(Rejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "shutdown signal is not available",
+            )),
+        }
+    }
+}
+
+
+//=============================================================================
+// SIGINT（Ctrl+C）またはSIGTERMを受信するまで待つ
+//=============================================================================
+pub(crate) async fn wait_for_signal()
+{
+    let ctrl_c = async
+    {
+        if tokio::signal::ctrl_c().await.is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+
+        match signal(SignalKind::terminate())
+        {
+            Ok(mut sigterm) => { sigterm.recv().await; },
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select!
+    {
+        _ = ctrl_c => info!("received SIGINT"),
+        _ = terminate => info!("received SIGTERM"),
+    }
+}
@@ -0,0 +1,392 @@
+use crate::middleware::{ self, Middleware, MiddlewareLayer, Next };
+use crate::shutdown::ShutdownSignal;
+
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::body::{ self, Body, BoxBody, Bytes, HttpBody };
+use axum::extract::{ FromRequest, RequestParts };
+use axum::http::{ header, HeaderMap, HeaderValue, Request };
+use axum::response::{ IntoResponse, Response };
+
+use futures_util::stream::Stream;
+
+use tokio::time::{ Instant, Sleep };
+
+
+// keep-aliveのコメントを送る間隔の初期値
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+
+//=============================================================================
+// Event
+//
+// Server-Sent Eventsで送るイベント
+// ```
+// Event::default().id("42").event("update").data("{\"count\":1}")
+// ```
+//=============================================================================
+#[derive(Debug, Clone, Default)]
+pub struct Event
+{
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event
+{
+    //=========================================================================
+    // イベントのID（再接続時にLast-Event-IDとして送られる）
+    //=========================================================================
+    pub fn id<T>(mut self, id: T) -> Self
+        where
+            T: Into<String>,
+    {
+        self.id = Some(id.into());
+        self
+    }
+
+    //=========================================================================
+    // イベント名
+    //=========================================================================
+    pub fn event<T>(mut self, event: T) -> Self
+        where
+            T: Into<String>,
+    {
+        self.event = Some(event.into());
+        self
+    }
+
+    //=========================================================================
+    // データ（複数行は行ごとにdataフィールドとして送る）
+    //=========================================================================
+    pub fn data<T>(mut self, data: T) -> Self
+        where
+            T: Into<String>,
+    {
+        self.data = Some(data.into());
+        self
+    }
+
+    //=========================================================================
+    // クライアントが再接続するまでの時間
+    //=========================================================================
+    pub fn retry(mut self, retry: Duration) -> Self
+    {
+        self.retry = Some(retry);
+        self
+    }
+
+    //=========================================================================
+    // コメント（クライアントには通知されない）
+    //=========================================================================
+    pub fn comment<T>(mut self, comment: T) -> Self
+        where
+            T: Into<String>,
+    {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    //=========================================================================
+    // text/event-streamの形式に変換
+    //=========================================================================
+    fn encode(&self) -> Bytes
+    {
+        let mut buf = String::new();
+
+        if let Some(comment) = &self.comment
+        {
+            for line in comment.lines()
+            {
+                let _ = writeln!(buf, ": {}", line);
+            }
+        }
+        // idとeventは1行で送るため改行を取り除く
+        if let Some(id) = &self.id
+        {
+            let _ = writeln!(buf, "id: {}", single_line(id));
+        }
+        if let Some(event) = &self.event
+        {
+            let _ = writeln!(buf, "event: {}", single_line(event));
+        }
+        if let Some(data) = &self.data
+        {
+            for line in data.split('\n')
+            {
+                let _ = writeln!(buf, "data: {}", line.trim_end_matches('\r'));
+            }
+        }
+        if let Some(retry) = self.retry
+        {
+            let _ = writeln!(buf, "retry: {}", retry.as_millis());
+        }
+        buf.push('\n');
+
+        Bytes::from(buf)
+    }
+}
+
+
+//=============================================================================
+// 改行を取り除く
+//=============================================================================
+fn single_line(value: &str) -> String
+{
+    value.chars().filter(|c| *c != '\n' && *c != '\r').collect()
+}
+
+
+//=============================================================================
+// Sse
+//
+// イベントのストリームをtext/event-streamとして返すレスポンス。
+// ストリームが終わるか、クライアントが切断するか、サーバのシャットダウンが
+// 通知されると終了する。
+// ```
+// async fn events(LastEventId(last): LastEventId) -> Sse<impl Stream<Item = Event>>
+// {
+//      let start = last.and_then(|id| id.parse().ok()).unwrap_or(0);
+//      let stream = futures_util::stream::iter(start..)
+//          .then(|n| async move
+//          {
+//              tokio::time::sleep(Duration::from_secs(1)).await;
+//              Event::default().id(n.to_string()).data(n.to_string())
+//          });
+//      Sse::new(stream)
+// }
+// ```
+//=============================================================================
+pub struct Sse<S>
+{
+    stream: S,
+    keep_alive: Option<Duration>,
+}
+
+impl<S> Sse<S>
+    where
+        S: Stream<Item = Event> + Send + 'static,
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub fn new(stream: S) -> Self
+    {
+        Self
+        {
+            stream,
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+        }
+    }
+
+    //=========================================================================
+    // keep-aliveのコメントを送る間隔（Noneなら送らない）
+    //=========================================================================
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> Self
+    {
+        self.keep_alive = interval;
+        self
+    }
+}
+
+impl<S> IntoResponse for Sse<S>
+    where
+        S: Stream<Item = Event> + Send + 'static,
+{
+    fn into_response(self) -> Response
+    {
+        let body = SseBody
+        {
+            stream: Box::pin(self.stream),
+            keep_alive: self.keep_alive.map(|interval|
+            {
+                (interval, Box::pin(tokio::time::sleep(interval)))
+            }),
+        };
+
+        let mut response = Response::new(body::boxed(body));
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+        // layer()がシャットダウンの通知で終了させる
+        response.extensions_mut().insert(EventStream);
+        response
+    }
+}
+
+
+//=============================================================================
+// Sseのレスポンスに付ける目印
+//=============================================================================
+#[derive(Debug, Clone, Copy)]
+struct EventStream;
+
+
+//=============================================================================
+// Sseのストリームをシャットダウンの通知で終了させるLayer
+//
+// コネクションのShutdownSignalをリクエストのextensionsから取り出し、
+// Sseが返したレスポンスのボディに設定する。
+//=============================================================================
+pub(crate) fn layer() -> MiddlewareLayer
+{
+    middleware::layer(SseShutdown)
+}
+
+
+//=============================================================================
+// SseShutdown
+//=============================================================================
+struct SseShutdown;
+
+#[async_trait]
+impl Middleware for SseShutdown
+{
+    async fn handle(&self, request: Request<Body>, next: Next) -> Response
+    {
+        let shutdown = request.extensions().get::<ShutdownSignal>().cloned();
+        let response = next.run(request).await;
+
+        match (response.extensions().get::<EventStream>(), shutdown)
+        {
+            (Some(_), Some(mut shutdown)) => response.map(|body|
+            {
+                body::boxed(UntilShutdown
+                {
+                    body,
+                    shutdown: Box::pin(async move { shutdown.recv().await }),
+                })
+            }),
+            _ => response,
+        }
+    }
+}
+
+
+// シャットダウンの通知を待つFuture
+type ShutdownFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+
+//=============================================================================
+// UntilShutdown
+//
+// シャットダウンが通知されたら終了するボディ
+//=============================================================================
+struct UntilShutdown
+{
+    body: BoxBody,
+    shutdown: ShutdownFuture,
+}
+
+impl HttpBody for UntilShutdown
+{
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Self::Data, Self::Error>>>
+    {
+        if self.shutdown.as_mut().poll(cx).is_ready()
+        {
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Result<Option<HeaderMap>, Self::Error>>
+    {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+}
+
+
+//=============================================================================
+// SseBody
+//=============================================================================
+struct SseBody<S>
+{
+    stream: Pin<Box<S>>,
+    keep_alive: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<S> HttpBody for SseBody<S>
+    where
+        S: Stream<Item = Event>,
+{
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Self::Data, Self::Error>>>
+    {
+        match self.stream.as_mut().poll_next(cx)
+        {
+            Poll::Ready(Some(event)) =>
+            {
+                if let Some((interval, sleep)) = self.keep_alive.as_mut()
+                {
+                    sleep.as_mut().reset(Instant::now() + *interval);
+                }
+                return Poll::Ready(Some(Ok(event.encode())));
+            },
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {},
+        }
+
+        // 一定時間イベントがなければコメントを送ってコネクションを維持する
+        if let Some((interval, sleep)) = self.keep_alive.as_mut()
+        {
+            if sleep.as_mut().poll(cx).is_ready()
+            {
+                sleep.as_mut().reset(Instant::now() + *interval);
+                return Poll::Ready(Some(Ok(Bytes::from_static(b":\n\n"))));
+            }
+        }
+
+        Poll::Pending
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, _cx: &mut Context<'_>)
+        -> Poll<Result<Option<HeaderMap>, Self::Error>>
+    {
+        Poll::Ready(Ok(None))
+    }
+}
+
+
+//=============================================================================
+// LastEventId
+//
+// 再接続したクライアントが最後に受け取ったイベントのID
+//=============================================================================
+#[derive(Debug, Clone)]
+pub struct LastEventId(pub Option<String>);
+
+#[async_trait]
+impl<B> FromRequest<B> for LastEventId
+    where
+        B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        let id = req.headers()
+            .get("last-event-id")
+            .and_then(|id| id.to_str().ok())
+            .map(|id| id.to_string());
+
+        Ok(Self(id))
+    }
+}
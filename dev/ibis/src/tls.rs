@@ -0,0 +1,794 @@
+use crate::config::{ IbisTlsClientAuth, IbisTlsConfig };
+use crate::shutdown::ShutdownSignal;
+
+use std::fs::{ self, File };
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime };
+
+use anyhow::{ anyhow, bail, Context, Result };
+
+use chrono::{ DateTime, TimeZone, Utc };
+
+use axum::body::Body;
+use axum::handler::Handler;
+use axum::http::{ header, HeaderValue, Request, Response, StatusCode, Uri };
+use axum::Router;
+
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient,
+    AllowAnyAuthenticatedClient,
+};
+use tokio_rustls::rustls::{ Certificate, PrivateKey, RootCertStore, ServerConfig };
+use tokio::sync::watch;
+
+use tokio_rustls::TlsAcceptor;
+
+use tracing::{ info, warn };
+
+
+//=============================================================================
+// 設定からTlsAcceptorを作成
+//
+// alpnは[tls]セクションで指定がなければサーバのkindに応じた値を使う。
+//=============================================================================
+pub(crate) fn acceptor(config: &IbisTlsConfig, default_alpn: &[&str]) -> Result<TlsAcceptor>
+{
+    let certs = load_certs(&config.cert_path)?;
+    let key = load_key(&config.key_path)?;
+
+    // クライアント証明書の検証（mTLS）
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match config.client_auth
+    {
+        IbisTlsClientAuth::None => builder.with_no_client_auth(),
+        IbisTlsClientAuth::Optional => builder.with_client_cert_verifier(
+            AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(&config.client_ca_path)?)
+        ),
+        IbisTlsClientAuth::Required => builder.with_client_cert_verifier(
+            AllowAnyAuthenticatedClient::new(load_roots(&config.client_ca_path)?)
+        ),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .with_context(|| format!("invalid certificate or private key: {}", config.cert_path))?;
+
+    server_config.alpn_protocols = match config.alpn.is_empty()
+    {
+        true => default_alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        false => config.alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+    };
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+
+//=============================================================================
+// 証明書の有効期限を取得（チェーンの先頭のサーバ証明書）
+//=============================================================================
+pub(crate) fn certificate_expiry(path: &str) -> Result<DateTime<Utc>>
+{
+    let certs = load_certs(path)?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs[0].0)
+        .map_err(|e| anyhow!("can't parse certificate: {}: {}", path, e))?;
+
+    let not_after = cert.validity().not_after.timestamp();
+    Utc.timestamp_opt(not_after, 0)
+        .single()
+        .ok_or_else(|| anyhow!("invalid expiry in certificate: {}", path))
+}
+
+
+//=============================================================================
+// 証明書の有効期限をログに出力
+//=============================================================================
+pub(crate) fn log_expiry(path: &str)
+{
+    match certificate_expiry(path)
+    {
+        Ok(expiry) if expiry <= Utc::now() =>
+        {
+            warn!("tls certificate has expired: {} (expired: {})", path, expiry.to_rfc3339());
+        },
+        Ok(expiry) =>
+        {
+            info!("tls certificate: {} (expires: {})", path, expiry.to_rfc3339());
+        },
+        Err(e) => warn!("{:#}", e),
+    }
+}
+
+
+//=============================================================================
+// 証明書の再読み込み
+//
+// 証明書ファイルの更新（reload_intervalごとに確認）またはSIGHUPの受信で
+// TlsAcceptorを作り直して差し替える。以降のハンドシェイクから新しい
+// 証明書が使われ、確立済みのコネクションはそのまま処理を続ける。
+// 読み込みに失敗した場合は現在の証明書を使い続ける。
+//=============================================================================
+pub(crate) async fn watch(
+    config: IbisTlsConfig,
+    alpn: Vec<&'static str>,
+    sender: watch::Sender<TlsAcceptor>,
+    mut shutdown: ShutdownSignal,
+)
+{
+    let mut interval = match config.reload_interval
+    {
+        0 => None,
+        secs => Some(tokio::time::interval(Duration::from_secs(secs))),
+    };
+    let mut hangup = Hangup::new();
+
+    // 書き込み途中のファイルを読まないように、更新が止まってから再読み込みする
+    let mut loaded = modified(&config);
+    let mut seen = loaded.clone();
+
+    loop
+    {
+        let tick = async
+        {
+            match interval.as_mut()
+            {
+                Some(interval) => { interval.tick().await; },
+                None => std::future::pending::<()>().await,
+            }
+        };
+
+        let reason = tokio::select!
+        {
+            _ = tick =>
+            {
+                let current = modified(&config);
+                let changed = current != loaded && current == seen;
+                seen = current;
+                if !changed
+                {
+                    continue;
+                }
+                "certificate files changed"
+            },
+            _ = hangup.recv() => "received SIGHUP",
+            _ = shutdown.recv() => break,
+        };
+
+        info!("reloading tls certificate ({})", reason);
+        match acceptor(&config, &alpn)
+        {
+            Ok(acceptor) =>
+            {
+                loaded = modified(&config);
+                seen = loaded.clone();
+                let _ = sender.send(acceptor);
+                log_expiry(&config.cert_path);
+            },
+            Err(e) => warn!("failed to reload tls certificate: {:#}", e),
+        }
+    }
+}
+
+
+//=============================================================================
+// 証明書関連ファイルの更新日時
+//=============================================================================
+fn modified(config: &IbisTlsConfig) -> Vec<Option<SystemTime>>
+{
+    [&config.cert_path, &config.key_path, &config.client_ca_path]
+        .iter()
+        .filter(|path| !path.is_empty())
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+
+//=============================================================================
+// Hangup
+//
+// SIGHUPの受信（unix以外では受信しない）
+//=============================================================================
+struct Hangup
+{
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    fn new() -> Self
+    {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{ signal, SignalKind };
+
+            Self { signal: signal(SignalKind::hangup()).ok() }
+        }
+
+        #[cfg(not(unix))]
+        {
+            Self {}
+        }
+    }
+
+    //=========================================================================
+    // SIGHUPを受信するまで待つ
+    //=========================================================================
+    async fn recv(&mut self)
+    {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut()
+        {
+            if signal.recv().await.is_some()
+            {
+                return;
+            }
+        }
+
+        std::future::pending::<()>().await
+    }
+}
+
+
+//=============================================================================
+// 証明書チェーンを読み込み
+//=============================================================================
+pub(crate) fn load_certs(path: &str) -> Result<Vec<Certificate>>
+{
+    let file = File::open(path)
+        .with_context(|| format!("can't open certificate: {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("can't parse certificate: {}", path))?;
+
+    if certs.is_empty()
+    {
+        bail!("no certificate found in {}", path);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+
+//=============================================================================
+// 秘密鍵を読み込み（PKCS#8, RSA, SEC1）
+//=============================================================================
+fn load_key(path: &str) -> Result<PrivateKey>
+{
+    let file = File::open(path)
+        .with_context(|| format!("can't open private key: {}", path))?;
+    let mut reader = BufReader::new(file);
+
+    loop
+    {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("can't parse private key: {}", path))?;
+
+        match item
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key found in {}", path),
+        }
+    }
+}
+
+
+//=============================================================================
+// クライアント証明書を検証するCAを読み込み
+//=============================================================================
+fn load_roots(path: &str) -> Result<RootCertStore>
+{
+    if path.is_empty()
+    {
+        bail!("client_ca_path is required when client_auth is enabled");
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)?
+    {
+        roots.add(&cert)
+            .map_err(|e| anyhow!("invalid CA certificate in {}: {}", path, e))?;
+    }
+    Ok(roots)
+}
+
+
+//=============================================================================
+// HTTPをHTTPSへリダイレクトするRouterを作成
+//=============================================================================
+pub(crate) fn redirect_router(https_port: String) -> Router<Body>
+{
+    let redirect = move |request: Request<Body>| async move
+    {
+        // Hostヘッダのポートを差し替えてhttpsのURLを作る
+        let host = request.headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<axum::http::uri::Authority>().ok())
+            .map(|authority| authority.host().to_string());
+
+        let host = match host
+        {
+            Some(host) => host,
+            None => return status_response(StatusCode::BAD_REQUEST),
+        };
+
+        let authority = match https_port.as_str()
+        {
+            "443" => host,
+            port => format!("{}:{}", host, port),
+        };
+        let path = request.uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+
+        let location = Uri::builder()
+            .scheme("https")
+            .authority(authority.as_str())
+            .path_and_query(path)
+            .build()
+            .ok()
+            .and_then(|uri| HeaderValue::from_str(&uri.to_string()).ok());
+
+        match location
+        {
+            Some(location) =>
+            {
+                let mut response = status_response(StatusCode::PERMANENT_REDIRECT);
+                response.headers_mut().insert(header::LOCATION, location);
+                response
+            },
+            None => status_response(StatusCode::BAD_REQUEST),
+        }
+    };
+
+    Router::new().fallback(redirect.into_service())
+}
+
+
+//=============================================================================
+// ボディのないレスポンスを作成
+//=============================================================================
+fn status_response(status: StatusCode) -> Response<Body>
+{
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+
+//=============================================================================
+// テスト
+//
+// 証明書はrcgenでテストの実行時に作成し、一時ディレクトリに書き出す。
+//=============================================================================
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use crate::config::IbisServerTokioConfig;
+    use crate::http1::{ self, Http1Config };
+    use crate::shutdown::Shutdown;
+
+    use std::convert::Infallible;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    use rcgen::{ BasicConstraints, CertificateParams, IsCa };
+
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::{ TcpListener, TcpStream };
+    use tokio::task::JoinHandle;
+
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::{ ClientConfig, ServerName };
+    use tokio_rustls::TlsConnector;
+
+    use tower::ServiceExt;
+
+
+    // テストごとに別の一時ディレクトリを使うための連番
+    static DIR_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+
+    //=========================================================================
+    // 一時ディレクトリ（dropで削除する）
+    //=========================================================================
+    struct TempDir(PathBuf);
+
+    impl TempDir
+    {
+        fn new() -> Self
+        {
+            let path = std::env::temp_dir().join(format!(
+                "ibis-tls-{}-{}",
+                std::process::id(),
+                DIR_SEQ.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&path).expect("create temp dir");
+            Self(path)
+        }
+
+        fn write(&self, name: &str, content: &str) -> String
+        {
+            let path = self.0.join(name);
+            fs::write(&path, content).expect("write temp file");
+            path.to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir
+    {
+        fn drop(&mut self)
+        {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+
+    //=========================================================================
+    // PEMの証明書と秘密鍵
+    //=========================================================================
+    struct Pem
+    {
+        cert: String,
+        key: String,
+    }
+
+    //=========================================================================
+    // localhostの自己署名のサーバ証明書
+    //=========================================================================
+    fn server_cert() -> Pem
+    {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("generate server certificate");
+        Pem
+        {
+            cert: cert.serialize_pem().expect("serialize server certificate"),
+            key: cert.serialize_private_key_pem(),
+        }
+    }
+
+    //=========================================================================
+    // クライアント証明書を発行するCA
+    //=========================================================================
+    fn client_ca() -> rcgen::Certificate
+    {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).expect("generate client CA")
+    }
+
+    //=========================================================================
+    // CAが発行したクライアント証明書
+    //=========================================================================
+    fn client_cert(ca: &rcgen::Certificate) -> Pem
+    {
+        let cert = rcgen::Certificate::from_params(CertificateParams::new(vec!["client".to_string()]))
+            .expect("generate client certificate");
+        Pem
+        {
+            cert: cert.serialize_pem_with_signer(ca).expect("sign client certificate"),
+            key: cert.serialize_private_key_pem(),
+        }
+    }
+
+    //=========================================================================
+    // サーバ証明書をファイルに書き出して[tls]の設定を作成
+    //=========================================================================
+    fn tls_config(dir: &TempDir, server: &Pem) -> IbisTlsConfig
+    {
+        IbisTlsConfig
+        {
+            cert_path: dir.write("cert.pem", &server.cert),
+            key_path: dir.write("key.pem", &server.key),
+            ..IbisTlsConfig::default()
+        }
+    }
+
+    //=========================================================================
+    // PEMの文字列から証明書を読み込み
+    //=========================================================================
+    fn pem_certs(pem: &str) -> Vec<Certificate>
+    {
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .expect("parse certificate")
+            .into_iter()
+            .map(Certificate)
+            .collect()
+    }
+
+    //=========================================================================
+    // PEMの文字列から秘密鍵を読み込み
+    //=========================================================================
+    fn pem_key(pem: &str) -> PrivateKey
+    {
+        let key = rustls_pemfile::pkcs8_private_keys(&mut pem.as_bytes())
+            .expect("parse private key")
+            .remove(0);
+        PrivateKey(key)
+    }
+
+    //=========================================================================
+    // サーバ証明書を信頼するクライアントの設定
+    //=========================================================================
+    fn client_config(server: &Pem, identity: Option<&Pem>, alpn: &[&str]) -> ClientConfig
+    {
+        let mut roots = RootCertStore::empty();
+        for cert in pem_certs(&server.cert)
+        {
+            roots.add(&cert).expect("add root certificate");
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match identity
+        {
+            Some(identity) => builder
+                .with_single_cert(pem_certs(&identity.cert), pem_key(&identity.key))
+                .expect("client certificate"),
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        config
+    }
+
+    //=========================================================================
+    // 1つのコネクションを受け付けて"ok"を返すHTTPSサーバ
+    //
+    // ハンドシェイクの結果を返す。
+    //=========================================================================
+    async fn serve_once(acceptor: TlsAcceptor) -> (SocketAddr, JoinHandle<io::Result<()>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+
+        let handle = tokio::spawn(async move
+        {
+            let (socket, peer) = listener.accept().await?;
+            let stream = acceptor.accept(socket).await?;
+
+            let shutdown = Shutdown::new();
+            let service = tower::service_fn(|_request: Request<Body>| async
+            {
+                Ok::<_, Infallible>(Response::new(Body::from("ok")))
+            });
+            http1::serve_connection(
+                stream,
+                peer,
+                Http1Config::new(&IbisServerTokioConfig::default()),
+                service,
+                shutdown.signal(),
+            ).await;
+            Ok(())
+        });
+
+        (addr, handle)
+    }
+
+    //=========================================================================
+    // TLSで接続
+    //=========================================================================
+    async fn connect(addr: SocketAddr, config: ClientConfig) -> io::Result<TlsStream<TcpStream>>
+    {
+        let socket = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from("localhost").expect("server name");
+        TlsConnector::from(Arc::new(config)).connect(name, socket).await
+    }
+
+    //=========================================================================
+    // GETリクエストを送ってレスポンス全体を受け取る
+    //
+    // サーバはclose_notifyを送らずに閉じるので、UnexpectedEofも終端とする。
+    //=========================================================================
+    async fn get(stream: &mut TlsStream<TcpStream>) -> io::Result<String>
+    {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        loop
+        {
+            match stream.read(&mut buf).await
+            {
+                Ok(0) => break,
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    }
+
+    //=========================================================================
+    // ハンドシェイクしてHTTPのリクエストに応答できる
+    //=========================================================================
+    #[tokio::test]
+    async fn handshake()
+    {
+        let dir = TempDir::new();
+        let server = server_cert();
+        let acceptor = acceptor(&tls_config(&dir, &server), &["http/1.1"]).expect("acceptor");
+
+        let (addr, handle) = serve_once(acceptor).await;
+        let mut stream = connect(addr, client_config(&server, None, &[])).await.expect("connect");
+        let response = get(&mut stream).await.expect("response");
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+        handle.await.expect("join").expect("server handshake");
+    }
+
+    //=========================================================================
+    // ALPNでクライアントの希望するプロトコルを選ぶ
+    //=========================================================================
+    #[tokio::test]
+    async fn alpn()
+    {
+        let dir = TempDir::new();
+        let server = server_cert();
+        let mut config = tls_config(&dir, &server);
+
+        // [tls]のalpnが空ならサーバのkindに応じた値を使う
+        for (offered, expected) in [(&["h2", "http/1.1"][..], "h2"), (&["http/1.1"][..], "http/1.1")]
+        {
+            let acceptor = acceptor(&config, &["h2", "http/1.1"]).expect("acceptor");
+            let (addr, _handle) = serve_once(acceptor).await;
+            let stream = connect(addr, client_config(&server, None, offered)).await.expect("connect");
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(expected.as_bytes()));
+        }
+
+        // [tls]のalpnを指定すればそれだけを提示する
+        config.alpn = vec!["http/1.1".to_string()];
+        let acceptor = acceptor(&config, &["h2", "http/1.1"]).expect("acceptor");
+        let (addr, _handle) = serve_once(acceptor).await;
+        let stream = connect(addr, client_config(&server, None, &["h2", "http/1.1"])).await.expect("connect");
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    }
+
+    //=========================================================================
+    // client_auth = "optional": 証明書がなくても、CAが発行した証明書でも接続できる
+    //=========================================================================
+    #[tokio::test]
+    async fn client_auth_optional()
+    {
+        let dir = TempDir::new();
+        let server = server_cert();
+        let ca = client_ca();
+        let client = client_cert(&ca);
+
+        let mut config = tls_config(&dir, &server);
+        config.client_auth = IbisTlsClientAuth::Optional;
+        config.client_ca_path = dir.write("ca.pem", &ca.serialize_pem().expect("serialize CA"));
+
+        for identity in [None, Some(&client)]
+        {
+            let acceptor = acceptor(&config, &["http/1.1"]).expect("acceptor");
+            let (addr, handle) = serve_once(acceptor).await;
+            let mut stream = connect(addr, client_config(&server, identity, &[])).await.expect("connect");
+            let response = get(&mut stream).await.expect("response");
+
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            handle.await.expect("join").expect("server handshake");
+        }
+    }
+
+    //=========================================================================
+    // client_auth = "required": 証明書のないクライアントは拒否する
+    //=========================================================================
+    #[tokio::test]
+    async fn client_auth_required()
+    {
+        let dir = TempDir::new();
+        let server = server_cert();
+        let ca = client_ca();
+        let client = client_cert(&ca);
+
+        let mut config = tls_config(&dir, &server);
+        config.client_auth = IbisTlsClientAuth::Required;
+        config.client_ca_path = dir.write("ca.pem", &ca.serialize_pem().expect("serialize CA"));
+
+        // CAが発行した証明書があれば接続できる
+        let acceptor = acceptor(&config, &["http/1.1"]).expect("acceptor");
+        let (addr, handle) = serve_once(acceptor.clone()).await;
+        let mut stream = connect(addr, client_config(&server, Some(&client), &[])).await.expect("connect");
+        let response = get(&mut stream).await.expect("response");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        handle.await.expect("join").expect("server handshake");
+
+        // 証明書がなければサーバがハンドシェイクを拒否し、レスポンスは返らない
+        let (addr, handle) = serve_once(acceptor).await;
+        let response = match connect(addr, client_config(&server, None, &[])).await
+        {
+            Ok(mut stream) => get(&mut stream).await.unwrap_or_default(),
+            Err(_) => String::new(),
+        };
+        assert!(response.is_empty(), "{}", response);
+        assert!(handle.await.expect("join").is_err());
+
+        // client_ca_pathがなければ設定の誤り
+        config.client_ca_path = String::new();
+        assert!(super::acceptor(&config, &["http/1.1"]).is_err());
+    }
+
+    //=========================================================================
+    // HTTPのリクエストをHostのポートを差し替えたHTTPSのURLへ308でリダイレクト
+    //=========================================================================
+    #[tokio::test]
+    async fn redirect()
+    {
+        let cases = [
+            ("8443", "example.com:8080", "/a/b?c=1", "https://example.com:8443/a/b?c=1"),
+            ("443", "example.com:8080", "/", "https://example.com/"),
+            ("443", "example.com", "/path", "https://example.com/path"),
+            ("8443", "127.0.0.1:80", "/", "https://127.0.0.1:8443/"),
+        ];
+
+        for (port, host, path, location) in cases
+        {
+            let request = Request::builder()
+                .uri(path)
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .expect("request");
+            let response = redirect_router(port.to_string()).oneshot(request).await.expect("response");
+
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(response.headers()[header::LOCATION], location);
+        }
+
+        // Hostがなければ400
+        let request = Request::builder().uri("/").body(Body::empty()).expect("request");
+        let response = redirect_router("8443".to_string()).oneshot(request).await.expect("response");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    //=========================================================================
+    // 証明書ファイルが更新されたら新しい証明書に差し替える
+    //=========================================================================
+    #[tokio::test]
+    async fn reload()
+    {
+        let dir = TempDir::new();
+        let old = server_cert();
+        let mut config = tls_config(&dir, &old);
+        config.reload_interval = 1;
+
+        let (sender, mut receiver) = watch::channel(acceptor(&config, &["http/1.1"]).expect("acceptor"));
+        let shutdown = Shutdown::new();
+        let watcher = tokio::spawn(watch(config.clone(), vec!["http/1.1"], sender, shutdown.signal()));
+
+        // 最初の確認より後に書き換える
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let new = server_cert();
+        dir.write("cert.pem", &new.cert);
+        dir.write("key.pem", &new.key);
+
+        tokio::time::timeout(Duration::from_secs(10), receiver.changed())
+            .await
+            .expect("reload timed out")
+            .expect("watcher stopped");
+        let acceptor = receiver.borrow().clone();
+
+        // 新しい証明書だけを信頼するクライアントが接続できる
+        let (addr, handle) = serve_once(acceptor.clone()).await;
+        let mut stream = connect(addr, client_config(&new, None, &[])).await.expect("connect");
+        let response = get(&mut stream).await.expect("response");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        handle.await.expect("join").expect("server handshake");
+
+        // 古い証明書を信頼するクライアントは接続できない
+        let (addr, _handle) = serve_once(acceptor).await;
+        assert!(connect(addr, client_config(&old, None, &[])).await.is_err());
+
+        watcher.abort();
+    }
+}
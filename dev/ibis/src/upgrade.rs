@@ -0,0 +1,51 @@
+use crate::server::BoxIo;
+
+use tokio::sync::oneshot;
+
+
+//=============================================================================
+// プロトコルの切り替えを待つ組を作成
+//
+// ibisのHTTP/1.1の実装では、Connection: upgradeのリクエストに
+// OnUpgradeを追加する。ハンドラが101 Switching Protocolsを返すと、
+// レスポンスを書き出した後のコネクションがPendingから渡される。
+//=============================================================================
+pub(crate) fn pending() -> (Pending, OnUpgrade)
+{
+    let (sender, receiver) = oneshot::channel();
+    (Pending(sender), OnUpgrade(receiver))
+}
+
+
+//=============================================================================
+// Pending
+//=============================================================================
+pub(crate) struct Pending(oneshot::Sender<BoxIo>);
+
+impl Pending
+{
+    //=========================================================================
+    // 切り替えたコネクションを渡す
+    //=========================================================================
+    pub(crate) fn fulfill(self, io: BoxIo)
+    {
+        let _ = self.0.send(io);
+    }
+}
+
+
+//=============================================================================
+// OnUpgrade
+//=============================================================================
+pub(crate) struct OnUpgrade(oneshot::Receiver<BoxIo>);
+
+impl OnUpgrade
+{
+    //=========================================================================
+    // コネクションが渡されるまで待つ（切り替わらなければNone）
+    //=========================================================================
+    pub(crate) async fn wait(self) -> Option<BoxIo>
+    {
+        self.0.await.ok()
+    }
+}
@@ -0,0 +1,86 @@
+use askama::Template;
+
+use axum::body::{ boxed, Full };
+use axum::http::{ header, HeaderValue, StatusCode };
+use axum::response::{ IntoResponse, Response };
+
+use tracing::error;
+
+
+//=============================================================================
+// View
+//
+// askamaのテンプレートをレンダリングしてHTMLのレスポンスを返す。
+// テンプレートはaskama.tomlの`dirs`で指定したディレクトリ（views/）から
+// コンパイル時に読み込まれる。実行時には読み込まないため、ディレクトリを
+// IbisConfig（config.toml）で変更することはできず、変更には再ビルドが必要。
+// ```
+// #[derive(askama::Template)]
+// #[template(path = "index.html")]
+// struct IndexTemplate {}
+//
+// async fn index() -> ibis::View<IndexTemplate>
+// {
+//      ibis::View::new(IndexTemplate {})
+// }
+// ```
+//=============================================================================
+pub struct View<T>
+{
+    template: T,
+    status: StatusCode,
+}
+
+impl<T> View<T>
+    where
+        T: Template,
+{
+    //=========================================================================
+    // コンストラクタ
+    //=========================================================================
+    pub fn new(template: T) -> Self
+    {
+        Self
+        {
+            template,
+            status: StatusCode::OK,
+        }
+    }
+
+    //=========================================================================
+    // ステータスコードを指定
+    //=========================================================================
+    pub fn status(mut self, status: StatusCode) -> Self
+    {
+        self.status = status;
+        self
+    }
+}
+
+impl<T> IntoResponse for View<T>
+    where
+        T: Template,
+{
+    fn into_response(self) -> Response
+    {
+        match self.template.render()
+        {
+            Ok(html) =>
+            {
+                let mut response = Response::new(boxed(Full::from(html)));
+                *response.status_mut() = self.status;
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8")
+                );
+                response
+            },
+            Err(e) =>
+            {
+                // レンダリングに失敗したら500を返す
+                error!("failed to render template: {}", e);
+                crate::error::framework_error(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            },
+        }
+    }
+}
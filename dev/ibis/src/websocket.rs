@@ -0,0 +1,596 @@
+use crate::config::IbisWebSocketConfig;
+use crate::extract::Rejection;
+use crate::server::BoxIo;
+use crate::shutdown::ShutdownSignal;
+use crate::upgrade;
+
+use std::borrow::Cow;
+use std::future::Future;
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::body::{ self, Empty };
+use axum::extract::{ FromRequest, RequestParts };
+use axum::http::{ header, HeaderMap, HeaderValue, Method, StatusCode };
+use axum::response::Response;
+
+use futures_util::{ SinkExt, StreamExt };
+
+use tokio::sync::mpsc::{ self, error::TrySendError };
+use tokio::time::{ self, Instant };
+
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{ self, Role, WebSocketConfig };
+use tokio_tungstenite::WebSocketStream;
+
+use tracing::debug;
+
+
+// ハンドラとの間でバッファするメッセージの数
+const CHANNEL_SIZE: usize = 32;
+
+// シャットダウン時にクライアントのcloseフレームを待つ時間
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// シャットダウン時に送るcloseフレームのコード（Going Away）
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+
+//=============================================================================
+// Message
+//
+// WebSocketで送受信するメッセージ
+//=============================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message
+{
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+impl Message
+{
+    //=========================================================================
+    // tungsteniteのメッセージから変換
+    //
+    // pingへの応答はtungsteniteが、pongはdrive()が処理するため、
+    // 生のフレームと合わせてハンドラには渡さない。
+    //=========================================================================
+    fn from_protocol(message: protocol::Message) -> Option<Self>
+    {
+        match message
+        {
+            protocol::Message::Text(text) => Some(Self::Text(text)),
+            protocol::Message::Binary(data) => Some(Self::Binary(data)),
+            protocol::Message::Ping(_) | protocol::Message::Pong(_) => None,
+            protocol::Message::Close(frame) => Some(Self::Close(frame.map(|frame|
+            {
+                CloseFrame
+                {
+                    code: frame.code.into(),
+                    reason: frame.reason.into_owned(),
+                }
+            }))),
+            protocol::Message::Frame(_) => None,
+        }
+    }
+
+    //=========================================================================
+    // tungsteniteのメッセージへ変換
+    //=========================================================================
+    fn into_protocol(self) -> protocol::Message
+    {
+        match self
+        {
+            Self::Text(text) => protocol::Message::Text(text),
+            Self::Binary(data) => protocol::Message::Binary(data),
+            Self::Ping(data) => protocol::Message::Ping(data),
+            Self::Pong(data) => protocol::Message::Pong(data),
+            Self::Close(frame) => protocol::Message::Close(frame.map(CloseFrame::into_protocol)),
+        }
+    }
+}
+
+
+//=============================================================================
+// CloseFrame
+//=============================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame
+{
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame
+{
+    //=========================================================================
+    // tungsteniteのcloseフレームへ変換
+    //=========================================================================
+    fn into_protocol(self) -> protocol::CloseFrame<'static>
+    {
+        protocol::CloseFrame
+        {
+            code: CloseCode::from(self.code),
+            reason: Cow::Owned(self.reason),
+        }
+    }
+}
+
+
+//=============================================================================
+// WebSocketClosed
+//
+// 閉じたWebSocketにメッセージを送ろうとした
+//=============================================================================
+#[derive(Debug)]
+pub struct WebSocketClosed;
+
+impl std::fmt::Display for WebSocketClosed
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        f.write_str("websocket is closed")
+    }
+}
+
+impl std::error::Error for WebSocketClosed {}
+
+
+//=============================================================================
+// WebSocketUpgrade
+//
+// ハンドラの引数に指定するとWebSocketのハンドシェイクを行う。
+// ```
+// async fn chat(ws: ibis::WebSocketUpgrade) -> Response
+// {
+//      ws.on_upgrade(|mut socket| async move
+//      {
+//          while let Some(message) = socket.recv().await
+//          {
+//              if let Message::Text(text) = message
+//              {
+//                  let _ = socket.send(Message::Text(text)).await;
+//              }
+//          }
+//      })
+// }
+// ```
+//=============================================================================
+pub struct WebSocketUpgrade
+{
+    accept: HeaderValue,
+    on_upgrade: OnUpgrade,
+    config: IbisWebSocketConfig,
+    shutdown: Option<ShutdownSignal>,
+}
+
+// サーバの実装ごとのプロトコル切り替え
+enum OnUpgrade
+{
+    Ibis(upgrade::OnUpgrade),
+    Hyper(hyper::upgrade::OnUpgrade),
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for WebSocketUpgrade
+    where
+        B: Send,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection>
+    {
+        if req.method() != Method::GET
+        {
+            return Err(Rejection::new(StatusCode::METHOD_NOT_ALLOWED, "websocket requires GET"));
+        }
+
+        let headers = req.headers();
+        if !header_contains(headers, header::CONNECTION, "upgrade")
+            || !header_contains(headers, header::UPGRADE, "websocket")
+        {
+            return Err(Rejection::new(StatusCode::BAD_REQUEST, "not a websocket upgrade request"));
+        }
+        if !header_contains(headers, header::SEC_WEBSOCKET_VERSION, "13")
+        {
+            return Err(Rejection::new(StatusCode::BAD_REQUEST, "unsupported websocket version"));
+        }
+        let accept = headers
+            .get(header::SEC_WEBSOCKET_KEY)
+            .map(|key| derive_accept_key(key.as_bytes()))
+            .and_then(|accept| HeaderValue::from_str(&accept).ok())
+            .ok_or_else(|| Rejection::new(StatusCode::BAD_REQUEST, "missing sec-websocket-key"))?;
+
+        // HTTP/2などプロトコルを切り替えられないコネクション
+        let extensions = req.extensions_mut();
+        let on_upgrade = match extensions.remove::<upgrade::OnUpgrade>()
+        {
+            Some(on_upgrade) => OnUpgrade::Ibis(on_upgrade),
+            None => match extensions.remove::<hyper::upgrade::OnUpgrade>()
+            {
+                Some(on_upgrade) => OnUpgrade::Hyper(on_upgrade),
+                None => return Err(Rejection::new(
+                    StatusCode::BAD_REQUEST,
+                    "connection does not support upgrade",
+                )),
+            },
+        };
+
+        Ok(Self
+        {
+            accept,
+            on_upgrade,
+            config: extensions.get::<IbisWebSocketConfig>().cloned().unwrap_or_default(),
+            shutdown: extensions.get::<ShutdownSignal>().cloned(),
+        })
+    }
+}
+
+impl WebSocketUpgrade
+{
+    //=========================================================================
+    // ハンドシェイクのレスポンスを返し、切り替え後にcallbackを呼び出す
+    //=========================================================================
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+        where
+            F: FnOnce(WebSocket) -> Fut + Send + 'static,
+            Fut: Future<Output = ()> + Send + 'static,
+    {
+        let Self { accept, on_upgrade, config, shutdown } = self;
+
+        tokio::spawn(async move
+        {
+            let io: BoxIo = match on_upgrade
+            {
+                OnUpgrade::Ibis(on_upgrade) => match on_upgrade.wait().await
+                {
+                    Some(io) => io,
+                    None => return,
+                },
+                OnUpgrade::Hyper(on_upgrade) => match on_upgrade.await
+                {
+                    Ok(upgraded) => Box::new(upgraded),
+                    Err(e) =>
+                    {
+                        debug!("websocket upgrade failed: {}", e);
+                        return;
+                    },
+                },
+            };
+
+            let stream = WebSocketStream::from_raw_socket(
+                io,
+                Role::Server,
+                Some(WebSocketConfig
+                {
+                    max_frame_size: Some(config.max_frame_size),
+                    max_message_size: Some(config.max_message_size),
+                    ..WebSocketConfig::default()
+                }),
+            ).await;
+
+            let (incoming_sender, incoming) = mpsc::channel(CHANNEL_SIZE);
+            let (outgoing, outgoing_receiver) = mpsc::channel(CHANNEL_SIZE);
+            let ping_interval = match config.ping_interval
+            {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            };
+
+            let socket = WebSocket { incoming, sender: WebSocketSender(outgoing) };
+            tokio::join!(
+                drive(stream, incoming_sender, outgoing_receiver, ping_interval, shutdown),
+                callback(socket),
+            );
+        });
+
+        let mut response = Response::new(body::boxed(Empty::new()));
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept);
+        response
+    }
+}
+
+
+//=============================================================================
+// ヘッダに指定のトークンが含まれるか
+//=============================================================================
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool
+{
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+
+//=============================================================================
+// WebSocket
+//
+// ハンドラに渡されるWebSocket。recv()がNoneを返したら閉じている。
+// recv()で受け取られずにバッファを超えたメッセージは捨てられる。
+// dropすると通常のcloseフレームを送って閉じる。
+//=============================================================================
+pub struct WebSocket
+{
+    incoming: mpsc::Receiver<Message>,
+    sender: WebSocketSender,
+}
+
+impl WebSocket
+{
+    //=========================================================================
+    // メッセージを受信
+    //=========================================================================
+    pub async fn recv(&mut self) -> Option<Message>
+    {
+        self.incoming.recv().await
+    }
+
+    //=========================================================================
+    // メッセージを送信
+    //=========================================================================
+    pub async fn send(&self, message: Message) -> Result<(), WebSocketClosed>
+    {
+        self.sender.send(message).await
+    }
+
+    //=========================================================================
+    // 別のタスクから送信するためのWebSocketSenderを取得
+    //=========================================================================
+    pub fn sender(&self) -> WebSocketSender
+    {
+        self.sender.clone()
+    }
+}
+
+
+//=============================================================================
+// WebSocketSender
+//=============================================================================
+#[derive(Clone)]
+pub struct WebSocketSender(mpsc::Sender<Message>);
+
+impl WebSocketSender
+{
+    //=========================================================================
+    // メッセージを送信
+    //=========================================================================
+    pub async fn send(&self, message: Message) -> Result<(), WebSocketClosed>
+    {
+        self.0.send(message).await.map_err(|_| WebSocketClosed)
+    }
+}
+
+
+//=============================================================================
+// WebSocketのコネクションを処理する
+//
+// 受信したメッセージをハンドラへ渡し、ハンドラからのメッセージを送信する。
+// ハンドラが受信しない間もpingやシャットダウンを処理できるように、
+// ハンドラへのチャンネルがいっぱいであれば受信したメッセージは捨てる。
+// ping_intervalごとにpingを送り、次のpingまでに何も受信しなければ閉じる。
+// シャットダウンが通知されたらGoing Awayのcloseフレームを送って閉じる。
+//=============================================================================
+async fn drive(
+    mut stream: WebSocketStream<BoxIo>,
+    incoming: mpsc::Sender<Message>,
+    mut outgoing: mpsc::Receiver<Message>,
+    ping_interval: Option<Duration>,
+    mut shutdown: Option<ShutdownSignal>,
+)
+{
+    let mut ping = ping_interval.map(|period| time::interval_at(Instant::now() + period, period));
+    let mut alive = true;
+    let mut closing = false;
+
+    loop
+    {
+        let tick = async
+        {
+            match ping.as_mut()
+            {
+                Some(ping) => { ping.tick().await; },
+                None => std::future::pending::<()>().await,
+            }
+        };
+        let stop = async
+        {
+            match shutdown.as_mut()
+            {
+                Some(shutdown) => shutdown.recv().await,
+                None => std::future::pending::<()>().await,
+            }
+        };
+
+        tokio::select!
+        {
+            // クライアントからのメッセージ（closeへの応答はtungsteniteが送る）
+            received = stream.next() =>
+            {
+                match received
+                {
+                    Some(Ok(message)) =>
+                    {
+                        alive = true;
+                        if let Some(message) = Message::from_protocol(message)
+                        {
+                            if let Err(TrySendError::Full(_)) = incoming.try_send(message)
+                            {
+                                debug!("websocket handler is not receiving, message dropped");
+                            }
+                        }
+                    },
+                    Some(Err(e)) =>
+                    {
+                        debug!("websocket error: {}", e);
+                        break;
+                    },
+                    None => break,
+                }
+            },
+
+            // ハンドラからのメッセージ（WebSocketがdropされたら閉じる）
+            message = outgoing.recv(), if !closing =>
+            {
+                let result = match message
+                {
+                    Some(message) => stream.send(message.into_protocol()).await,
+                    None =>
+                    {
+                        closing = true;
+                        stream.close(None).await
+                    },
+                };
+                if let Err(e) = result
+                {
+                    debug!("websocket error: {}", e);
+                    break;
+                }
+            },
+
+            _ = tick =>
+            {
+                if !alive
+                {
+                    debug!("websocket ping timed out");
+                    break;
+                }
+                alive = false;
+                if stream.send(protocol::Message::Ping(Vec::new())).await.is_err()
+                {
+                    break;
+                }
+            },
+
+            _ = stop =>
+            {
+                let frame = CloseFrame
+                {
+                    code: CLOSE_GOING_AWAY,
+                    reason: "server shutting down".to_string(),
+                };
+                if stream.close(Some(frame.into_protocol())).await.is_ok()
+                {
+                    let _ = time::timeout(CLOSE_TIMEOUT, async
+                    {
+                        while let Some(Ok(_)) = stream.next().await {}
+                    }).await;
+                }
+                break;
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    use crate::shutdown::Shutdown;
+
+    use tokio::io::DuplexStream;
+
+
+    // クライアント側のWebSocket
+    type Client = WebSocketStream<DuplexStream>;
+
+
+    //=========================================================================
+    // サーバからのメッセージを受信（止まった場合はパニックする）
+    //=========================================================================
+    async fn next(client: &mut Client)
+        -> Option<Result<protocol::Message, tokio_tungstenite::tungstenite::Error>>
+    {
+        time::timeout(Duration::from_secs(10), client.next())
+            .await
+            .expect("websocket connection stalled")
+    }
+
+
+    //=========================================================================
+    // 送信だけのハンドラでもpingを送り続け、シャットダウンで1001を送る
+    //
+    // ハンドラへのチャンネルの容量を超えるpingとメッセージを受信しても、
+    // 送信とシャットダウンの処理が止まらないことを確かめる。
+    //=========================================================================
+    #[tokio::test(start_paused = true)]
+    async fn send_only_handler_survives_pings_and_shutdown()
+    {
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let stream = WebSocketStream::from_raw_socket(
+            Box::new(server_io) as BoxIo,
+            Role::Server,
+            None,
+        ).await;
+        let mut client: Client = WebSocketStream::from_raw_socket(client_io, Role::Client, None).await;
+
+        let (incoming_sender, incoming) = mpsc::channel(CHANNEL_SIZE);
+        let (outgoing, outgoing_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let socket = WebSocket { incoming, sender: WebSocketSender(outgoing) };
+
+        let shutdown = Shutdown::new();
+        let driver = tokio::spawn(drive(
+            stream,
+            incoming_sender,
+            outgoing_receiver,
+            Some(Duration::from_secs(1)),
+            Some(shutdown.signal()),
+        ));
+
+        // ハンドラが受信しないメッセージ
+        for n in 0..CHANNEL_SIZE * 2
+        {
+            client.send(protocol::Message::Text(n.to_string())).await.unwrap();
+        }
+
+        // pingのたびにハンドラから送信する
+        let mut pings = 0;
+        while pings <= CHANNEL_SIZE * 2
+        {
+            match next(&mut client).await
+            {
+                Some(Ok(protocol::Message::Ping(_))) =>
+                {
+                    pings += 1;
+                    socket.send(Message::Text(format!("push {}", pings))).await.unwrap();
+                },
+                Some(Ok(protocol::Message::Text(text))) =>
+                {
+                    assert_eq!(text, format!("push {}", pings));
+                },
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        // シャットダウンでGoing Awayのcloseフレームが届く
+        let drain = tokio::spawn(shutdown.drain(Duration::from_secs(30)));
+        let code = loop
+        {
+            match next(&mut client).await
+            {
+                Some(Ok(protocol::Message::Close(frame))) => break frame.map(|frame| frame.code),
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected message: {:?}", other),
+            }
+        };
+        assert_eq!(code, Some(CloseCode::from(CLOSE_GOING_AWAY)));
+        while let Some(Ok(_)) = next(&mut client).await {}
+
+        driver.await.unwrap();
+        assert!(drain.await.unwrap());
+        drop(socket);
+    }
+}